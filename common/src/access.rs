@@ -0,0 +1,200 @@
+//! Access control for protocol handlers, based on the node id of the peer.
+//!
+//! The access list is a text file with one entry per line:
+//!
+//! ```text
+//! # comments start with a hash
+//! allow <node id>
+//! deny <node id>
+//! ```
+//!
+//! If the file contains any `allow` entries, only those nodes are accepted.
+//! `deny` entries are always rejected. The file is reloaded when it changes.
+//! Rejected connections are closed with [`ACCESS_DENIED`] and a reason.
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use iroh::{
+    endpoint::{Connection, VarInt},
+    protocol::ProtocolHandler,
+    NodeId,
+};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::limits::{find_remote_close, RemoteClose};
+
+/// Application close code for connections rejected by the access list
+pub const ACCESS_DENIED: u32 = 403;
+
+/// How often the access list file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A parsed access list
+#[derive(Debug, Default, Clone)]
+pub struct AccessList {
+    /// If present, only these nodes are allowed
+    allow: Option<BTreeSet<NodeId>>,
+    /// These nodes are always rejected
+    deny: BTreeSet<NodeId>,
+}
+
+impl AccessList {
+    /// Parse an access list from the contents of a file
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut list = Self::default();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (kind, node_id) = line
+                .split_once(char::is_whitespace)
+                .with_context(|| format!("line {}: expected `allow|deny <node id>`", i + 1))?;
+            let node_id: NodeId = node_id
+                .trim()
+                .parse()
+                .with_context(|| format!("line {}: invalid node id", i + 1))?;
+            match kind {
+                "allow" => {
+                    list.allow.get_or_insert_with(BTreeSet::new).insert(node_id);
+                }
+                "deny" => {
+                    list.deny.insert(node_id);
+                }
+                other => bail!("line {}: unknown entry {:?}", i + 1, other),
+            }
+        }
+        Ok(list)
+    }
+
+    /// Load an access list from a file
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read access list {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("invalid access list {}", path.display()))
+    }
+
+    /// Check a node, returns the reason if it is rejected
+    pub fn check(&self, node_id: &NodeId) -> Result<(), &'static str> {
+        if self.deny.contains(node_id) {
+            return Err("node is on the deny list");
+        }
+        match &self.allow {
+            Some(allow) if !allow.contains(node_id) => Err("node is not on the allow list"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An access list that can be shared between handlers and reloaded at runtime
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    path: PathBuf,
+    list: Arc<RwLock<AccessList>>,
+}
+
+impl AccessControl {
+    /// Load the access list from a file
+    pub fn from_file(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let list = AccessList::load(&path)?;
+        Ok(Self {
+            path,
+            list: Arc::new(RwLock::new(list)),
+        })
+    }
+
+    /// Reload the access list from its file
+    ///
+    /// If the file is invalid, the previous list stays in effect.
+    pub fn reload(&self) -> Result<()> {
+        let list = AccessList::load(&self.path)?;
+        *self.list.write().unwrap() = list;
+        Ok(())
+    }
+
+    /// Check a node, returns the reason if it is rejected
+    pub fn check(&self, node_id: &NodeId) -> Result<(), &'static str> {
+        self.list.read().unwrap().check(node_id)
+    }
+
+    /// Spawn a task that reloads the access list whenever the file changes
+    pub fn spawn_reload_task(&self) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let modified =
+                |path: &Path| -> Option<SystemTime> { path.metadata().ok()?.modified().ok() };
+            let mut last_modified = modified(&this.path);
+            loop {
+                tokio::time::sleep(RELOAD_INTERVAL).await;
+                let current = modified(&this.path);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                match this.reload() {
+                    Ok(()) => info!("Reloaded access list {}", this.path.display()),
+                    Err(cause) => warn!("Keeping previous access list: {:?}", cause),
+                }
+            }
+        })
+    }
+}
+
+/// Wraps a protocol handler, and rejects connections from nodes that are
+/// not allowed by the access list before they reach it
+#[derive(Debug, Clone)]
+pub struct AccessLimited<P> {
+    inner: P,
+    access: AccessControl,
+}
+
+impl<P> AccessLimited<P> {
+    pub fn new(inner: P, access: AccessControl) -> Self {
+        Self { inner, access }
+    }
+}
+
+impl<P: ProtocolHandler> ProtocolHandler for AccessLimited<P> {
+    fn accept(
+        &self,
+        conn: Connection,
+    ) -> Pin<Box<dyn std::future::Future<Output = Result<()>> + Send + 'static>> {
+        let node_id = match conn.remote_node_id() {
+            Ok(node_id) => node_id,
+            Err(cause) => {
+                let cause = anyhow::Error::from(cause);
+                return Box::pin(async move { Err(cause) });
+            }
+        };
+        if let Err(reason) = self.access.check(&node_id) {
+            info!("Rejecting connection from {}: {}", node_id, reason);
+            conn.close(VarInt::from_u32(ACCESS_DENIED), reason.as_bytes());
+            return Box::pin(async { Ok(()) });
+        }
+        self.inner.accept(conn)
+    }
+
+    fn shutdown(&self) -> Pin<Box<dyn std::future::Future<Output = ()> + Send + 'static>> {
+        self.inner.shutdown()
+    }
+}
+
+/// If the error was caused by the remote node rejecting us, the reason it gave
+///
+/// The rejection is also found when it surfaces as an error of a stream that
+/// was open on the connection.
+pub fn denied_reason(err: &anyhow::Error) -> Option<String> {
+    match find_remote_close(err, ACCESS_DENIED)? {
+        RemoteClose::Connection(reason) => Some(reason),
+        // only connections are closed with ACCESS_DENIED
+        RemoteClose::Stream => None,
+    }
+}
@@ -0,0 +1,23 @@
+//! Command line parsing helpers
+//!
+//! The exercises parse their arguments by hand, options and flags are taken
+//! out of the argument list before the positional arguments are matched.
+use anyhow::Result;
+
+/// Removes a `--name <value>` option from the argument list and returns its value.
+pub fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>> {
+    let Some(i) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    anyhow::ensure!(i + 1 < args.len(), "missing value for {}", name);
+    let value = args.remove(i + 1);
+    args.remove(i);
+    Ok(Some(value))
+}
+
+/// Removes a `--name` flag from the argument list and returns whether it was present.
+pub fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != len
+}
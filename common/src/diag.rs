@@ -0,0 +1,89 @@
+//! Connection diagnostics, enabled with `--diag`
+//!
+//! Reports which path connections take and when it changes, for example
+//! when a relayed connection is upgraded to a direct hole-punched one, and
+//! prints the QUIC statistics of a connection when it is done. Clients that
+//! leave their connections to the blobs downloader only watch the paths.
+use std::{collections::HashMap, time::Duration};
+
+use iroh::{
+    endpoint::{ConnectionStats, ConnectionType},
+    Endpoint, NodeId,
+};
+use tokio::{task::JoinHandle, time::Instant};
+
+/// How often the connection type of the remote nodes is checked
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Describe a connection type, `None` if there is no path yet
+fn describe(conn_type: &ConnectionType) -> Option<String> {
+    match conn_type {
+        ConnectionType::Direct(addr) => Some(format!("direct ({})", addr)),
+        ConnectionType::Relay(url) => Some(format!("relayed ({})", url)),
+        ConnectionType::Mixed(addr, url) => {
+            Some(format!("mixed, direct {} and relayed {}", addr, url))
+        }
+        ConnectionType::None => None,
+    }
+}
+
+/// Describe the path currently used to talk to a node
+pub fn describe_path(ep: &Endpoint, node_id: NodeId) -> String {
+    ep.remote_info(node_id)
+        .and_then(|info| describe(&info.conn_type))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Spawn a task that prints the path to every remote node whenever it changes
+///
+/// The task runs until it is aborted.
+pub fn watch_paths(ep: Endpoint) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let start = Instant::now();
+        let mut paths = HashMap::<NodeId, String>::new();
+        loop {
+            for info in ep.remote_info_iter() {
+                let Some(path) = describe(&info.conn_type) else {
+                    continue;
+                };
+                let elapsed = start.elapsed().as_secs_f64();
+                let node = info.node_id.fmt_short();
+                match paths.get(&info.node_id) {
+                    Some(previous) if *previous == path => continue,
+                    Some(previous) => println!(
+                        "[{:8.3}s] {}: path changed from {} to {}",
+                        elapsed, node, previous, path
+                    ),
+                    None => println!("[{:8.3}s] {}: path is {}", elapsed, node, path),
+                }
+                paths.insert(info.node_id, path);
+            }
+            tokio::time::sleep(WATCH_INTERVAL).await;
+        }
+    })
+}
+
+/// Print the QUIC statistics of a connection
+pub fn print_stats(stats: &ConnectionStats) {
+    let path = &stats.path;
+    println!("Connection stats:");
+    println!(
+        "  RTT:                {:.2} ms",
+        path.rtt.as_secs_f64() * 1000.0
+    );
+    println!("  Congestion window:  {} bytes", path.cwnd);
+    println!("  Congestion events:  {}", path.congestion_events);
+    println!("  Current MTU:        {} bytes", path.current_mtu);
+    println!(
+        "  Sent:               {} packets, {} bytes in {} datagrams",
+        path.sent_packets, stats.udp_tx.bytes, stats.udp_tx.datagrams
+    );
+    println!(
+        "  Received:           {} bytes in {} datagrams",
+        stats.udp_rx.bytes, stats.udp_rx.datagrams
+    );
+    println!(
+        "  Lost:               {} packets, {} bytes",
+        path.lost_packets, path.lost_bytes
+    );
+}
@@ -0,0 +1,150 @@
+//! Dialing with a timeout and retries
+//!
+//! A single dial often fails on flaky links, so [`connect`] retries with
+//! exponential backoff and jitter. After every failed attempt it prints why
+//! the attempt failed and which addresses were known for the node, along with
+//! the source that produced each of them.
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use iroh::{
+    endpoint::{ConnectOptions, Connection},
+    Endpoint, NodeAddr, NodeId,
+};
+use rand::Rng;
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::cli::take_option;
+
+/// How to dial a node
+#[derive(Debug, Clone)]
+pub struct DialOptions {
+    /// How long a single attempt may take
+    pub timeout: Duration,
+    /// How many attempts to make before giving up
+    pub max_attempts: u32,
+    /// How long to wait after the first failed attempt, doubled every time
+    pub initial_backoff: Duration,
+    /// The longest time to wait between attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for DialOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl DialOptions {
+    /// Take the `--dial-timeout`, `--max-attempts` and `--backoff` options
+    /// from the argument list, using the defaults for missing ones
+    pub fn from_args(args: &mut Vec<String>) -> Result<Self> {
+        let defaults = Self::default();
+        Ok(Self {
+            timeout: take_option(args, "--dial-timeout")?
+                .map(|ms| ms.parse::<u64>())
+                .transpose()
+                .context("invalid --dial-timeout")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.timeout),
+            max_attempts: take_option(args, "--max-attempts")?
+                .map(|max| max.parse())
+                .transpose()
+                .context("invalid --max-attempts")?
+                .unwrap_or(defaults.max_attempts),
+            initial_backoff: take_option(args, "--backoff")?
+                .map(|ms| ms.parse::<u64>())
+                .transpose()
+                .context("invalid --backoff")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.initial_backoff),
+            max_backoff: defaults.max_backoff,
+        })
+    }
+}
+
+/// Describe the addresses the endpoint knows for a node, and where they came from
+fn describe_addrs(ep: &Endpoint, node_id: NodeId) -> Vec<String> {
+    let Some(info) = ep.remote_info(node_id) else {
+        return Vec::new();
+    };
+    let relay = info
+        .relay_url
+        .map(|relay| format!("relay {}", relay.relay_url));
+    let direct = info.addrs.into_iter().map(|addr| {
+        let sources = addr
+            .sources
+            .keys()
+            .map(|source| format!("{:?}", source))
+            .collect::<Vec<_>>();
+        format!("direct {} (from {})", addr.addr, sources.join(", "))
+    });
+    relay.into_iter().chain(direct).collect()
+}
+
+/// Connect to a node, retrying with exponential backoff and jitter
+///
+/// All `alpns` are offered, most preferred first. The server picks one of
+/// them, [`Connection::alpn`] tells which.
+pub async fn connect(
+    ep: &Endpoint,
+    addr: impl Into<NodeAddr>,
+    alpns: &[&[u8]],
+    options: &DialOptions,
+) -> Result<Connection> {
+    let (alpn, additional) = alpns.split_first().context("no ALPN to offer")?;
+    let additional: Vec<Vec<u8>> = additional.iter().map(|alpn| alpn.to_vec()).collect();
+    let addr = addr.into();
+    let node_id = addr.node_id;
+    let max_attempts = options.max_attempts.max(1);
+    let mut backoff = options.initial_backoff;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let start = Instant::now();
+        let dial = async {
+            let opts = ConnectOptions::new().with_additional_alpns(additional.clone());
+            let connecting = ep.connect_with_opts(addr.clone(), alpn, opts).await?;
+            anyhow::Ok(connecting.await?)
+        };
+        let cause = match tokio::time::timeout(options.timeout, dial).await {
+            Ok(Ok(conn)) => {
+                info!("Connected on attempt {} in {:?}", attempt, start.elapsed());
+                return Ok(conn);
+            }
+            Ok(Err(cause)) => cause,
+            Err(_) => anyhow!("timed out after {:?}", options.timeout),
+        };
+        eprintln!(
+            "Attempt {}/{} to connect to {} failed after {:.2} s: {:#}",
+            attempt,
+            max_attempts,
+            node_id.fmt_short(),
+            start.elapsed().as_secs_f64(),
+            cause
+        );
+        let addrs = describe_addrs(ep, node_id);
+        if addrs.is_empty() {
+            eprintln!("  no addresses known for the node");
+        }
+        for addr in addrs {
+            eprintln!("  {}", addr);
+        }
+        if attempt == max_attempts {
+            return Err(cause.context(format!("giving up after {} attempts", max_attempts)));
+        }
+
+        // Wait between half and one and a half times the backoff
+        let jitter = rand::thread_rng().gen_range(0.5..1.5);
+        let wait = backoff.mul_f64(jitter);
+        eprintln!("  retrying in {:.2} s", wait.as_secs_f64());
+        tokio::time::sleep(wait).await;
+        backoff = (backoff * 2).min(options.max_backoff);
+    }
+}
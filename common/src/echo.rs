@@ -0,0 +1,423 @@
+//! The echo service and its versions
+//!
+//! Every version has its own ALPN, and a server serves all versions it
+//! supports side by side. Clients offer all versions they support, and the
+//! server picks the newest one they have in common during the handshake, see
+//! [`prefer_newest`]. Used by the echo3 server and clients, and by the node.
+//!
+//! - Version 1 is the framed wire format described in [`framing`].
+//! - Version 2 keeps the wire format of version 1, and the server starts
+//!   every connection with a [`Hello`] that tells the client its limits.
+//!
+//! The unversioned [`LEGACY_ECHO_ALPN`] is not a version. It speaks the
+//! original unframed format, and servers always serve it next to the
+//! versions, so that clients from before framing can still connect.
+use std::{fmt, pin::Pin, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use iroh::{
+    endpoint::{Connection, ConnectionError, VarInt},
+    protocol::ProtocolHandler,
+    Endpoint,
+};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+pub use crate::framing::LEGACY_ECHO_ALPN;
+use crate::{
+    framing,
+    limits::{Limiter, Limits, LIMIT_EXCEEDED},
+};
+
+/// The ALPN protocol identifier for version 1 of the echo service
+pub const ECHO_ALPN_V1: &[u8] = framing::ECHO_ALPN;
+
+/// The ALPN protocol identifier for version 2 of the echo service
+pub const ECHO_ALPN_V2: &[u8] = b"echo/2";
+
+/// A version of the echo protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    /// All supported versions, newest first
+    pub const ALL: [Self; 2] = [Self::V2, Self::V1];
+
+    /// The ALPN protocol identifier of this version
+    pub fn alpn(self) -> &'static [u8] {
+        match self {
+            Self::V1 => ECHO_ALPN_V1,
+            Self::V2 => ECHO_ALPN_V2,
+        }
+    }
+
+    /// The version spoken on a connection, from its negotiated ALPN
+    pub fn of(conn: &Connection) -> Option<Self> {
+        match conn.alpn().as_deref() {
+            Some(ECHO_ALPN_V1) => Some(Self::V1),
+            Some(ECHO_ALPN_V2) => Some(Self::V2),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(self.alpn()))
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "1" | "echo/1" => Ok(Self::V1),
+            "2" | "echo/2" => Ok(Self::V2),
+            _ => bail!("unknown echo version: {}", s),
+        }
+    }
+}
+
+/// The ALPNs for a set of versions, most preferred first
+pub fn alpns(versions: &[Version]) -> Vec<&'static [u8]> {
+    versions.iter().map(|version| version.alpn()).collect()
+}
+
+/// The ALPNs a server registers for a set of versions
+///
+/// Includes the legacy ALPN, which is served independent of the versions.
+pub fn server_alpns(versions: &[Version]) -> Vec<&'static [u8]> {
+    let mut alpns = alpns(versions);
+    alpns.push(LEGACY_ECHO_ALPN);
+    alpns
+}
+
+/// Make the server pick the newest version it has in common with a client
+///
+/// The handshake picks the first server ALPN that the client offers, and the
+/// router registers its ALPNs in sorted order, which puts `echo/1` before
+/// `echo/2`. Call this after spawning the router, with all ALPNs it serves,
+/// to move the versions to the front, newest first.
+pub fn prefer_newest(ep: &Endpoint, alpns: &[&[u8]]) -> Result<()> {
+    let mut alpns = alpns.to_vec();
+    alpns.sort_by_key(|alpn| {
+        Version::ALL
+            .iter()
+            .position(|version| version.alpn() == *alpn)
+            .unwrap_or(Version::ALL.len())
+    });
+    ep.set_alpns(alpns.into_iter().map(|alpn| alpn.to_vec()).collect())?;
+    Ok(())
+}
+
+/// Sent by version 2 servers on a uni-directional stream when a connection
+/// is accepted
+#[derive(Debug, Clone, Copy)]
+pub struct Hello {
+    /// Maximum size of a single framed message the server accepts
+    pub max_message_size: usize,
+}
+
+impl Hello {
+    async fn send(self, conn: &Connection) -> Result<()> {
+        // Frame sizes are u32, a larger maximum is the same as no maximum
+        let max_message_size = u32::try_from(self.max_message_size).unwrap_or(u32::MAX);
+        let mut send_stream = conn.open_uni().await?;
+        send_stream
+            .write_all(&max_message_size.to_be_bytes())
+            .await?;
+        send_stream.finish()?;
+        Ok(())
+    }
+
+    async fn recv(conn: &Connection) -> Result<Self> {
+        let mut recv_stream = conn.accept_uni().await?;
+        let mut buf = [0u8; 4];
+        recv_stream.read_exact(&mut buf).await?;
+        Ok(Self {
+            max_message_size: u32::from_be_bytes(buf) as usize,
+        })
+    }
+}
+
+/// What the client and the server agreed on
+#[derive(Debug, Clone, Copy)]
+pub struct Negotiated {
+    pub version: Version,
+    /// The server's hello, from version 2 on
+    pub hello: Option<Hello>,
+}
+
+impl Negotiated {
+    /// Client side - find out the version of a connection, and receive the
+    /// hello if the version has one
+    pub async fn new(conn: &Connection) -> Result<Self> {
+        let version = Version::of(conn).context("not an echo connection")?;
+        let hello = match version {
+            Version::V1 => None,
+            Version::V2 => Some(
+                Hello::recv(conn)
+                    .await
+                    .context("no hello from the server")?,
+            ),
+        };
+        Ok(Self { version, hello })
+    }
+
+    /// Fail early if the server is known to reject a message of this size
+    pub fn check_message_size(&self, size: usize) -> Result<()> {
+        if let Some(hello) = self.hello {
+            anyhow::ensure!(
+                size <= hello.max_message_size,
+                "message of {} bytes exceeds the server maximum of {} bytes",
+                size,
+                hello.max_message_size
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Negotiated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.hello {
+            Some(hello) => write!(
+                f,
+                "{}, server accepts messages up to {} bytes",
+                self.version, hello.max_message_size
+            ),
+            None => write!(f, "{}", self.version),
+        }
+    }
+}
+
+/// Echo protocol handler
+#[derive(Debug, Clone)]
+pub struct EchoProtocol {
+    /// Maximum size of a single framed message
+    max_message_size: usize,
+    /// Connection, stream and rate limits
+    limiter: Limiter,
+}
+
+impl Default for EchoProtocol {
+    fn default() -> Self {
+        Self::new(framing::DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+impl EchoProtocol {
+    /// Create a new echo protocol handler with the given maximum message size
+    pub fn new(max_message_size: usize) -> Self {
+        Self {
+            max_message_size,
+            limiter: Limiter::default(),
+        }
+    }
+
+    /// Enforce the given limits on connections and streams
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limiter = Limiter::new(limits);
+        self
+    }
+}
+
+impl ProtocolHandler for EchoProtocol {
+    fn accept(
+        &self,
+        conn: Connection,
+    ) -> Pin<Box<dyn std::future::Future<Output = Result<()>> + Send + 'static>> {
+        let max_message_size = self.max_message_size;
+        let limiter = self.limiter.clone();
+        Box::pin(async move {
+            let remote = conn.remote_node_id()?;
+            let permit = match limiter.try_connect(remote) {
+                Ok(permit) => permit,
+                Err(reason) => {
+                    info!("Rejecting connection from {}: {}", remote, reason);
+                    conn.close(VarInt::from_u32(LIMIT_EXCEEDED), reason.as_bytes());
+                    return Ok(());
+                }
+            };
+            let legacy = conn.alpn().as_deref() == Some(LEGACY_ECHO_ALPN);
+            if legacy {
+                info!("Legacy connection accepted from {}", remote);
+            } else {
+                let version = Version::of(&conn).context("not an echo connection")?;
+                info!("Connection accepted from {} using {}", remote, version);
+                if version >= Version::V2 {
+                    Hello { max_message_size }.send(&conn).await?;
+                }
+            }
+
+            // Echo unreliable datagrams as long as the connection is alive
+            let mut tasks = JoinSet::new();
+            let datagram_conn = conn.clone();
+            tasks.spawn(async move {
+                while let Ok(datagram) = datagram_conn.read_datagram().await {
+                    if let Err(cause) = datagram_conn.send_datagram(datagram) {
+                        warn!("Failed to echo datagram: {:?}", cause);
+                    }
+                }
+            });
+
+            // Accept bi-directional streams until the client closes the connection,
+            // and echo each of them in its own task
+            let res: Result<()> = loop {
+                let (mut send_stream, mut recv_stream) = match conn.accept_bi().await {
+                    Ok(streams) => streams,
+                    Err(ConnectionError::ApplicationClosed(_)) => break Ok(()),
+                    Err(cause) => break Err(cause.into()),
+                };
+                let stream_permit = match &permit.streams {
+                    Some(streams) => match streams.clone().try_acquire_owned() {
+                        Ok(stream_permit) => Some(stream_permit),
+                        Err(_) => {
+                            info!("Rejecting stream from {}: too many streams", remote);
+                            send_stream.reset(VarInt::from_u32(LIMIT_EXCEEDED)).ok();
+                            recv_stream.stop(VarInt::from_u32(LIMIT_EXCEEDED)).ok();
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                let bucket = permit.bucket.clone();
+                tasks.spawn(async move {
+                    let _stream_permit = stream_permit;
+                    let res = if legacy {
+                        framing::serve_legacy_stream(
+                            send_stream,
+                            recv_stream,
+                            max_message_size,
+                            bucket,
+                        )
+                        .await
+                    } else {
+                        framing::serve_stream(send_stream, recv_stream, max_message_size, bucket)
+                            .await
+                    };
+                    if let Err(cause) = res {
+                        warn!("Failed to echo stream: {:?}", cause);
+                    }
+                });
+            };
+
+            // Let in-flight streams finish, also if the connection failed
+            while tasks.join_next().await.is_some() {}
+            drop(permit);
+            info!("Connection closed");
+
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use iroh::{endpoint::ConnectOptions, protocol::Router, Endpoint, NodeAddr, RelayMode};
+
+    use super::*;
+
+    /// Connect offering the given ALPNs, most preferred first
+    async fn connect(ep: &Endpoint, addr: NodeAddr, alpns: &[&[u8]]) -> Result<Connection> {
+        let (alpn, additional) = alpns.split_first().context("no ALPN to offer")?;
+        let additional = additional.iter().map(|alpn| alpn.to_vec()).collect();
+        let opts = ConnectOptions::new().with_additional_alpns(additional);
+        let dial = async { anyhow::Ok(ep.connect_with_opts(addr, alpn, opts).await?.await?) };
+        tokio::time::timeout(Duration::from_secs(5), dial).await?
+    }
+
+    /// Start a router that serves the given versions, and the legacy ALPN
+    async fn serve(versions: &[Version]) -> Result<(Router, NodeAddr)> {
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let addr = ep.node_addr().await?;
+        let echo = EchoProtocol::default();
+        let mut builder = Router::builder(ep.clone());
+        for alpn in server_alpns(versions) {
+            builder = builder.accept(alpn, echo.clone());
+        }
+        let router = builder.spawn();
+        prefer_newest(&ep, &server_alpns(versions))?;
+        Ok((router, addr))
+    }
+
+    #[tokio::test]
+    async fn version_pairings() -> Result<()> {
+        // Including a client that lists the oldest version first
+        let subsets: [&[Version]; 4] = [
+            &[Version::V1],
+            &[Version::V2],
+            &Version::ALL,
+            &[Version::V1, Version::V2],
+        ];
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let message = b"pairing";
+
+        for server_versions in subsets {
+            let (router, addr) = serve(server_versions).await?;
+
+            for client_versions in subsets {
+                let res = connect(&client, addr.clone(), &alpns(client_versions)).await;
+                // ALL is sorted newest first
+                let newest_shared = Version::ALL.into_iter().find(|version| {
+                    client_versions.contains(version) && server_versions.contains(version)
+                });
+                let shared = newest_shared.is_some();
+                let conn = match res {
+                    Ok(conn) => conn,
+                    Err(_) if !shared => continue,
+                    Err(cause) => {
+                        return Err(cause.context(format!(
+                            "client {:?} failed against server {:?}",
+                            client_versions, server_versions
+                        )))
+                    }
+                };
+                assert!(
+                    shared,
+                    "client {:?} connected to server {:?} without a common version",
+                    client_versions, server_versions
+                );
+
+                let negotiated = Negotiated::new(&conn).await?;
+                assert_eq!(
+                    Some(negotiated.version),
+                    newest_shared,
+                    "client {:?} and server {:?} did not settle on the newest common version",
+                    client_versions,
+                    server_versions
+                );
+                assert_eq!(
+                    negotiated.hello.is_some(),
+                    negotiated.version >= Version::V2
+                );
+                let res = framing::send_message(&conn, message, message.len()).await?;
+                assert_eq!(res, message);
+                conn.close(0u8.into(), b"done");
+            }
+
+            // Clients from before framing work with every server
+            let conn = connect(&client, addr, &[LEGACY_ECHO_ALPN]).await?;
+            let res = framing::send_legacy_message(&conn, message, message.len()).await?;
+            assert_eq!(res, message);
+            conn.close(0u8.into(), b"done");
+
+            router.shutdown().await?;
+        }
+
+        client.close().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+//! Wire format of the echo service.
+//!
+//! On [`LEGACY_ECHO_ALPN`] the original format of the workshop is spoken: the
+//! client writes a single message and finishes the stream, the server reads
+//! it to the end and writes it back, see [`serve_legacy_stream`].
+//!
+//! On [`ECHO_ALPN`] every stream starts with a single [`Mode`] byte chosen by
+//! the client.
+//!
+//! In [`Mode::Framed`] both directions carry length-prefixed frames: a one
+//! byte tag followed by a big-endian `u32` length and the payload. Each
+//! message frame is echoed back as a message frame. If a frame exceeds the
+//! maximum message size, the server replies with an error frame and stops
+//! reading.
+//!
+//! In [`Mode::Streaming`] the bytes are copied back as they arrive, without
+//! any size limit. Flow control of the underlying QUIC stream provides
+//! backpressure.
+use std::{fmt, sync::Arc};
+
+use anyhow::{bail, Result};
+use iroh::endpoint::{Connection, ReadExactError, RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{info, warn};
+
+use crate::limits::TokenBucket;
+
+/// The ALPN protocol identifier of the framed wire format
+pub const ECHO_ALPN: &[u8] = b"echo/1";
+
+/// The ALPN protocol identifier of the original wire format
+pub const LEGACY_ECHO_ALPN: &[u8] = b"ECHO";
+
+/// Default maximum size of a single framed message
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Frame tag for a message payload
+const TAG_MESSAGE: u8 = 0;
+/// Frame tag for an error reply, the payload is a UTF-8 reason
+const TAG_ERROR: u8 = 1;
+
+/// How the bytes on a stream are interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Length-prefixed messages, see the module docs
+    Framed = 0,
+    /// Raw bytes, echoed as they arrive
+    Streaming = 1,
+}
+
+impl Mode {
+    /// Read the mode byte at the start of a stream
+    pub async fn read(recv: &mut RecvStream) -> Result<Self> {
+        let mut buf = [0u8; 1];
+        recv.read_exact(&mut buf).await?;
+        match buf[0] {
+            0 => Ok(Self::Framed),
+            1 => Ok(Self::Streaming),
+            other => bail!("unknown stream mode: {}", other),
+        }
+    }
+
+    /// Write the mode byte at the start of a stream
+    pub async fn write(self, send: &mut SendStream) -> Result<()> {
+        send.write_all(&[self as u8]).await?;
+        Ok(())
+    }
+}
+
+/// A single frame in [`Mode::Framed`]
+#[derive(Debug)]
+pub enum Frame {
+    /// A message to be echoed, or the echo of a message
+    Message(Vec<u8>),
+    /// An error reply from the remote side
+    Error(String),
+}
+
+/// Error returned by [`read_frame`] if a frame exceeds the maximum size
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "message too large: {} bytes, maximum is {} bytes",
+            self.size, self.max
+        )
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+/// Write a single frame
+pub async fn write_frame(send: &mut SendStream, frame: &Frame) -> Result<()> {
+    let (tag, payload) = match frame {
+        Frame::Message(data) => (TAG_MESSAGE, data.as_slice()),
+        Frame::Error(reason) => (TAG_ERROR, reason.as_bytes()),
+    };
+    let len = u32::try_from(payload.len())?;
+    let mut header = [0u8; 5];
+    header[0] = tag;
+    header[1..].copy_from_slice(&len.to_be_bytes());
+    send.write_all(&header).await?;
+    send.write_all(payload).await?;
+    Ok(())
+}
+
+/// Read a single frame
+///
+/// Returns `None` if the stream was finished cleanly at a frame boundary.
+/// Fails with [`FrameTooLarge`] before reading the payload if the announced
+/// size exceeds `max_size`.
+pub async fn read_frame(recv: &mut RecvStream, max_size: usize) -> Result<Option<Frame>> {
+    let mut header = [0u8; 5];
+    match recv.read_exact(&mut header).await {
+        Ok(()) => {}
+        Err(ReadExactError::FinishedEarly(0)) => return Ok(None),
+        Err(cause) => return Err(cause.into()),
+    }
+    let size = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+    if size > max_size {
+        return Err(FrameTooLarge {
+            size,
+            max: max_size,
+        }
+        .into());
+    }
+    let mut payload = vec![0u8; size];
+    recv.read_exact(&mut payload).await?;
+    match header[0] {
+        TAG_MESSAGE => Ok(Some(Frame::Message(payload))),
+        TAG_ERROR => Ok(Some(Frame::Error(
+            String::from_utf8_lossy(&payload).into_owned(),
+        ))),
+        other => bail!("unknown frame tag: {}", other),
+    }
+}
+
+/// Server side - echo everything received on a bi-directional stream
+///
+/// If a token bucket is given, reading from the stream is throttled to its rate.
+pub async fn serve_stream(
+    mut send_stream: SendStream,
+    mut recv_stream: RecvStream,
+    max_message_size: usize,
+    bucket: Option<Arc<TokenBucket>>,
+) -> Result<()> {
+    match Mode::read(&mut recv_stream).await? {
+        Mode::Framed => loop {
+            match read_frame(&mut recv_stream, max_message_size).await {
+                Ok(Some(Frame::Message(msg))) => {
+                    info!("Received message: {}", String::from_utf8_lossy(&msg));
+                    if let Some(bucket) = &bucket {
+                        bucket.consume(msg.len() as u64).await;
+                    }
+                    // Echo the message back
+                    write_frame(&mut send_stream, &Frame::Message(msg)).await?;
+                }
+                Ok(Some(Frame::Error(reason))) => bail!("client sent an error: {}", reason),
+                Ok(None) => break,
+                Err(cause) => {
+                    let Some(too_large) = cause.downcast_ref::<FrameTooLarge>() else {
+                        return Err(cause);
+                    };
+                    warn!("Rejecting message: {}", too_large);
+                    write_frame(&mut send_stream, &Frame::Error(too_large.to_string())).await?;
+                    // We are not going to read the rest of the payload
+                    recv_stream.stop(0u8.into()).ok();
+                    break;
+                }
+            }
+        },
+        Mode::Streaming => {
+            // Copy the bytes back as they arrive
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut n = 0;
+            while let Some(read) = recv_stream.read(&mut buf).await? {
+                if let Some(bucket) = &bucket {
+                    bucket.consume(read as u64).await;
+                }
+                send_stream.write_all(&buf[..read]).await?;
+                n += read;
+            }
+            info!("Echoed {} bytes", n);
+        }
+    }
+    send_stream.finish()?;
+    Ok(())
+}
+
+/// Server side - echo a single message in the legacy format
+///
+/// The message is read to the end, so it must not exceed `max_message_size`.
+/// If a token bucket is given, the message is throttled to its rate.
+pub async fn serve_legacy_stream(
+    mut send_stream: SendStream,
+    mut recv_stream: RecvStream,
+    max_message_size: usize,
+    bucket: Option<Arc<TokenBucket>>,
+) -> Result<()> {
+    // Read the message
+    let msg = recv_stream.read_to_end(max_message_size).await?;
+    info!("Received message: {}", String::from_utf8_lossy(&msg));
+    if let Some(bucket) = &bucket {
+        bucket.consume(msg.len() as u64).await;
+    }
+
+    // Echo the message back
+    send_stream.write_all(&msg).await?;
+    send_stream.finish()?;
+    Ok(())
+}
+
+/// Client side - send a single message in the legacy format and return the echo
+pub async fn send_legacy_message(
+    conn: &Connection,
+    message: &[u8],
+    max_message_size: usize,
+) -> Result<Vec<u8>> {
+    // Open a bi-directional stream
+    let (mut send_stream, mut recv_stream) = conn.open_bi().await?;
+
+    // Send the message
+    send_stream.write_all(message).await?;
+    send_stream.finish()?;
+
+    // Wait for the response
+    let res = recv_stream.read_to_end(max_message_size).await?;
+    Ok(res)
+}
+
+/// Client side - send a single message on a new stream and return the echo
+pub async fn send_message(
+    conn: &Connection,
+    message: &[u8],
+    max_message_size: usize,
+) -> Result<Vec<u8>> {
+    // Open a bi-directional stream
+    let (mut send_stream, mut recv_stream) = conn.open_bi().await?;
+
+    // Send the message
+    Mode::Framed.write(&mut send_stream).await?;
+    write_frame(&mut send_stream, &Frame::Message(message.to_vec())).await?;
+    send_stream.finish()?;
+
+    // Wait for the response
+    match read_frame(&mut recv_stream, max_message_size).await? {
+        Some(Frame::Message(res)) => Ok(res),
+        Some(Frame::Error(reason)) => bail!("server rejected message: {}", reason),
+        None => bail!("server closed the stream without a response"),
+    }
+}
+
+/// Client side - stream all bytes from `reader` through a new stream, and
+/// write the echo to `writer` as it arrives
+///
+/// Returns the number of bytes sent and received.
+pub async fn stream_through(
+    conn: &Connection,
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<(u64, u64)> {
+    // Open a bi-directional stream
+    let (mut send_stream, mut recv_stream) = conn.open_bi().await?;
+    Mode::Streaming.write(&mut send_stream).await?;
+
+    // Sending and receiving must happen concurrently, otherwise both sides
+    // block as soon as the flow control windows are full
+    let send = async {
+        let n = tokio::io::copy(&mut reader, &mut send_stream).await?;
+        send_stream.finish()?;
+        anyhow::Ok(n)
+    };
+    let recv = async {
+        let n = tokio::io::copy(&mut recv_stream, &mut writer).await?;
+        anyhow::Ok(n)
+    };
+    tokio::try_join!(send, recv)
+}
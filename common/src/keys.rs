@@ -0,0 +1,546 @@
+//! Named identity profiles, so a node keeps its node id across restarts.
+//!
+//! Each profile is a key file `<name>.key` in the keys directory, readable
+//! only by the current user. A key file is a single line
+//!
+//! ```text
+//! iroh-key-v1 <node id> plain <secret key hex>
+//! iroh-key-v1 <node id> encrypted <salt hex> <nonce hex> <ciphertext hex>
+//! ```
+//!
+//! Encrypted key files derive the encryption key from a passphrase using
+//! argon2, and encrypt the secret key with ChaCha20-Poly1305. The node id is
+//! stored in the clear so profiles can be listed without the passphrase.
+//!
+//! A profile can also be created from a BIP39 mnemonic, so the key can be
+//! backed up on paper and restored from the words. The secret key is the
+//! SLIP-10 ed25519 master key of the BIP39 seed, so the same words and seed
+//! passphrase always give the same node id.
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use argon2::Argon2;
+use bip39::{Language, Mnemonic};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use hmac::{Hmac, Mac};
+use iroh_base::{PublicKey, SecretKey};
+use rand::{thread_rng, Rng};
+use sha2::Sha512;
+use tracing::warn;
+
+use crate::cli::take_flag;
+
+/// Version header of the key file format
+const HEADER: &str = "iroh-key-v1";
+
+/// Directory containing the key files
+///
+/// This is `$IROH_CONFIG_DIR/keys` if the environment variable is set, and
+/// `iroh-workshop/keys` in the platform config directory otherwise.
+pub fn keys_dir() -> Result<PathBuf> {
+    let base = match env::var_os("IROH_CONFIG_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => dirs::config_dir()
+            .context("unable to determine the config directory")?
+            .join("iroh-workshop"),
+    };
+    Ok(base.join("keys"))
+}
+
+/// Path of the key file for a profile
+fn profile_path(name: &str) -> Result<PathBuf> {
+    ensure!(
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "invalid profile name {:?}, use letters, digits, '-' and '_'",
+        name
+    );
+    Ok(keys_dir()?.join(format!("{}.key", name)))
+}
+
+/// Information about a stored profile that is available without the passphrase
+#[derive(Debug)]
+pub struct ProfileInfo {
+    pub name: String,
+    pub node_id: PublicKey,
+    pub encrypted: bool,
+    pub path: PathBuf,
+}
+
+/// A parsed key file
+enum KeyFile {
+    Plain(SecretKey),
+    Encrypted {
+        salt: Vec<u8>,
+        nonce: Vec<u8>,
+        ciphertext: Vec<u8>,
+    },
+}
+
+fn parse_key_file(contents: &str) -> Result<(PublicKey, KeyFile)> {
+    let parts = contents.split_whitespace().collect::<Vec<_>>();
+    match parts.as_slice() {
+        [HEADER, node_id, "plain", secret] => {
+            let bytes: [u8; 32] = hex::decode(secret)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("invalid secret key length"))?;
+            Ok((
+                node_id.parse()?,
+                KeyFile::Plain(SecretKey::from_bytes(&bytes)),
+            ))
+        }
+        [HEADER, node_id, "encrypted", salt, nonce, ciphertext] => Ok((
+            node_id.parse()?,
+            KeyFile::Encrypted {
+                salt: hex::decode(salt)?,
+                nonce: hex::decode(nonce)?,
+                ciphertext: hex::decode(ciphertext)?,
+            },
+        )),
+        _ => bail!("unsupported key file format"),
+    }
+}
+
+/// Derive the file encryption key from a passphrase
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|cause| anyhow::anyhow!("key derivation failed: {}", cause))?;
+    Ok(key)
+}
+
+fn encrypt(secret_key: &SecretKey, passphrase: &str) -> Result<String> {
+    let salt = thread_rng().gen::<[u8; 16]>();
+    let nonce = thread_rng().gen::<[u8; 12]>();
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), secret_key.to_bytes().as_slice())
+        .map_err(|_| anyhow::anyhow!("encryption failed"))?;
+    Ok(format!(
+        "{} {} encrypted {} {} {}\n",
+        HEADER,
+        secret_key.public(),
+        hex::encode(salt),
+        hex::encode(nonce),
+        hex::encode(ciphertext)
+    ))
+}
+
+fn decrypt(salt: &[u8], nonce: &[u8], ciphertext: &[u8], passphrase: &str) -> Result<SecretKey> {
+    ensure!(nonce.len() == 12, "invalid nonce length");
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, salt)?);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("wrong passphrase or corrupted key file"))?;
+    let bytes: [u8; 32] = plaintext
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid secret key length"))?;
+    Ok(SecretKey::from_bytes(&bytes))
+}
+
+/// Reads a passphrase from the IROH_PASSPHRASE environment variable, or
+/// prompts for it on the terminal.
+pub fn read_passphrase(prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = env::var("IROH_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    rpassword::prompt_password(prompt).context("failed to read passphrase")
+}
+
+/// Generate a new random 24 word mnemonic
+pub fn generate_mnemonic() -> Result<Mnemonic> {
+    let entropy = thread_rng().gen::<[u8; 32]>();
+    Ok(Mnemonic::from_entropy_in(Language::English, &entropy)?)
+}
+
+/// Parse a mnemonic, pointing out unknown words and checksum mismatches
+pub fn parse_mnemonic(words: &str) -> Result<Mnemonic> {
+    let words = words.split_whitespace().collect::<Vec<_>>();
+    let normalized = words.join(" ").to_lowercase();
+    Mnemonic::parse_in_normalized(Language::English, &normalized).map_err(|cause| match cause {
+        bip39::Error::UnknownWord(i) => {
+            anyhow!(
+                "word {} ({:?}) is not in the BIP39 word list",
+                i + 1,
+                words[i]
+            )
+        }
+        bip39::Error::InvalidChecksum => {
+            anyhow!("checksum mismatch, check the words for typos and their order")
+        }
+        bip39::Error::BadWordCount(n) => {
+            anyhow!("expected 12, 15, 18, 21 or 24 words, got {}", n)
+        }
+        cause => anyhow!("invalid mnemonic: {}", cause),
+    })
+}
+
+/// Derive the node secret key from a mnemonic and a seed passphrase
+///
+/// The seed passphrase may be empty.
+pub fn secret_key_from_mnemonic(mnemonic: &Mnemonic, seed_passphrase: &str) -> SecretKey {
+    let seed = mnemonic.to_seed(seed_passphrase);
+    let mut mac =
+        Hmac::<Sha512>::new_from_slice(b"ed25519 seed").expect("hmac accepts any key length");
+    mac.update(&seed);
+    let master = mac.finalize().into_bytes();
+    let bytes: [u8; 32] = master[..32].try_into().unwrap();
+    SecretKey::from_bytes(&bytes)
+}
+
+/// Reads a seed passphrase from the IROH_SEED_PASSPHRASE environment variable,
+/// or prompts for it on the terminal. An empty seed passphrase is allowed.
+fn read_seed_passphrase() -> Result<String> {
+    if let Ok(passphrase) = env::var("IROH_SEED_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    rpassword::prompt_password("Seed passphrase (leave empty for none): ")
+        .context("failed to read seed passphrase")
+}
+
+/// Reads the mnemonic words from the IROH_MNEMONIC environment variable, or
+/// from a line on stdin.
+fn read_mnemonic() -> Result<Mnemonic> {
+    let words = match env::var("IROH_MNEMONIC") {
+        Ok(words) => words,
+        Err(_) => {
+            eprintln!("Enter the mnemonic words, separated by spaces:");
+            let mut words = String::new();
+            std::io::stdin().read_line(&mut words)?;
+            words
+        }
+    };
+    parse_mnemonic(&words)
+}
+
+/// Asks for a new passphrase to encrypt a key file
+fn read_new_passphrase() -> Result<String> {
+    let passphrase = read_passphrase("New passphrase: ")?;
+    if env::var("IROH_PASSPHRASE").is_err() {
+        let repeated = read_passphrase("Repeat passphrase: ")?;
+        ensure!(passphrase == repeated, "passphrases do not match");
+    }
+    Ok(passphrase)
+}
+
+/// Create the keys directory, accessible only by the current user
+fn create_private_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// Create a new file, readable and writable only by the current user
+fn write_private_file(path: &Path, contents: &str) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn read_key_file(name: &str) -> Result<(PathBuf, PublicKey, KeyFile)> {
+    let path = profile_path(name)?;
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("profile {} not found at {}", name, path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            warn!(
+                "key file {} is accessible by other users (mode {:o})",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+    let (node_id, key_file) = parse_key_file(&contents)
+        .with_context(|| format!("invalid key file {}", path.display()))?;
+    Ok((path, node_id, key_file))
+}
+
+/// Store a secret key as a new profile, optionally encrypted with a passphrase
+///
+/// Fails if the profile already exists.
+pub fn save_profile(
+    name: &str,
+    secret_key: &SecretKey,
+    passphrase: Option<&str>,
+) -> Result<PathBuf> {
+    let path = profile_path(name)?;
+    ensure!(!path.exists(), "profile {} already exists", name);
+    create_private_dir(path.parent().unwrap())?;
+    let contents = match passphrase {
+        Some(passphrase) => encrypt(secret_key, passphrase)?,
+        None => format!(
+            "{} {} plain {}\n",
+            HEADER,
+            secret_key.public(),
+            hex::encode(secret_key.to_bytes())
+        ),
+    };
+    write_private_file(&path, &contents)?;
+    Ok(path)
+}
+
+/// Load the secret key of a profile, asking for the passphrase if it is encrypted
+pub fn load_profile(name: &str) -> Result<SecretKey> {
+    let (_, node_id, key_file) = read_key_file(name)?;
+    let secret_key = match key_file {
+        KeyFile::Plain(secret_key) => secret_key,
+        KeyFile::Encrypted {
+            salt,
+            nonce,
+            ciphertext,
+        } => {
+            let passphrase = read_passphrase(&format!("Passphrase for profile {}: ", name))?;
+            decrypt(&salt, &nonce, &ciphertext, &passphrase)?
+        }
+    };
+    ensure!(
+        secret_key.public() == node_id,
+        "key file for profile {} is inconsistent",
+        name
+    );
+    Ok(secret_key)
+}
+
+/// Load a profile, or create it with a new random key if it does not exist yet
+///
+/// New profiles are encrypted if the IROH_PASSPHRASE environment variable is set.
+pub fn load_or_create_profile(name: &str) -> Result<SecretKey> {
+    if profile_path(name)?.exists() {
+        return load_profile(name);
+    }
+    let secret_key = SecretKey::generate(&mut thread_rng());
+    let passphrase = env::var("IROH_PASSPHRASE").ok();
+    let path = save_profile(name, &secret_key, passphrase.as_deref())?;
+    // Not on stdout, which may carry data, for example in pipe mode
+    eprintln!("Created profile {} at {}", name, path.display());
+    Ok(secret_key)
+}
+
+/// Gets a secret key from the IROH_SECRET environment variable, from the profile named by the
+/// IROH_PROFILE environment variable, or generates a new random one.
+/// If IROH_SECRET is set, it must be a valid string representation of a secret key.
+/// If the profile does not exist yet, it is created with a new random key.
+pub fn get_or_generate_secret_key() -> Result<SecretKey> {
+    if let Ok(secret) = env::var("IROH_SECRET") {
+        // Parse the secret key from string
+        SecretKey::from_str(&secret).context("Invalid secret key format")
+    } else if let Ok(profile) = env::var("IROH_PROFILE") {
+        // Load the key from the key store
+        load_or_create_profile(&profile)
+    } else {
+        // Generate a new random key, stdout may carry data so report on stderr
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        eprintln!("Generated new random node key: {}", secret_key.public());
+        eprintln!(
+            "To keep the node id across restarts, create a profile with `{} key new <name>` and set the IROH_PROFILE environment variable to <name>",
+            env::args().next().unwrap_or_default()
+        );
+        Ok(secret_key)
+    }
+}
+
+/// Information about a single profile
+pub fn profile_info(name: &str) -> Result<ProfileInfo> {
+    let (path, node_id, key_file) = read_key_file(name)?;
+    Ok(ProfileInfo {
+        name: name.to_string(),
+        node_id,
+        encrypted: matches!(key_file, KeyFile::Encrypted { .. }),
+        path,
+    })
+}
+
+/// Information about all stored profiles, sorted by name
+pub fn list_profiles() -> Result<Vec<ProfileInfo>> {
+    let dir = keys_dir()?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut profiles = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("key") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        match profile_info(name) {
+            Ok(info) => profiles.push(info),
+            Err(cause) => warn!("Skipping {}: {:?}", path.display(), cause),
+        }
+    }
+    profiles.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(profiles)
+}
+
+/// Handles the `key` subcommand
+pub fn key_command(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let encrypt = take_flag(&mut args, "--encrypt");
+    let with_mnemonic = take_flag(&mut args, "--mnemonic");
+    let cmd = args.first().map(|x| x.to_lowercase()).unwrap_or_default();
+    match cmd.as_str() {
+        "new" | "restore" if args.len() == 2 => {
+            let name = &args[1];
+            let (secret_key, new_mnemonic) = if cmd == "restore" {
+                let mnemonic = read_mnemonic()?;
+                let secret_key = secret_key_from_mnemonic(&mnemonic, &read_seed_passphrase()?);
+                (secret_key, None)
+            } else if with_mnemonic {
+                let mnemonic = generate_mnemonic()?;
+                let secret_key = secret_key_from_mnemonic(&mnemonic, &read_seed_passphrase()?);
+                (secret_key, Some(mnemonic))
+            } else {
+                (SecretKey::generate(&mut thread_rng()), None)
+            };
+            let passphrase = if encrypt {
+                Some(read_new_passphrase()?)
+            } else {
+                None
+            };
+            // Only show the words once the profile is saved, so they always
+            // belong to a key that exists
+            let path = save_profile(name, &secret_key, passphrase.as_deref())?;
+            if let Some(mnemonic) = new_mnemonic {
+                println!("Write down these words, they are the only backup of the key:");
+                println!();
+                println!("{}", mnemonic);
+                println!();
+                println!("Restore with `key restore <name>` and the same seed passphrase.");
+            }
+            println!("Created profile {} at {}", name, path.display());
+            println!("Node ID: {}", secret_key.public());
+            println!(
+                "To use it, set the IROH_PROFILE environment variable to {}",
+                name
+            );
+        }
+        "list" if args.len() == 1 => {
+            for info in list_profiles()? {
+                let encrypted = if info.encrypted { " (encrypted)" } else { "" };
+                println!("{}\t{}{}", info.name, info.node_id, encrypted);
+            }
+        }
+        "show" if args.len() == 2 => {
+            let info = profile_info(&args[1])?;
+            println!("Profile: {}", info.name);
+            println!("Node ID: {}", info.node_id);
+            println!("Encrypted: {}", info.encrypted);
+            println!("Path: {}", info.path.display());
+        }
+        "export" if args.len() == 2 => {
+            // This is the only place where the secret is printed
+            let secret_key = load_profile(&args[1])?;
+            println!("{}", secret_key);
+        }
+        _ => {
+            println!("Key commands:");
+            println!("  key new <name> [--encrypt] [--mnemonic]");
+            println!("                               Create a new profile with a random key,");
+            println!("                               optionally backed up as mnemonic words");
+            println!("  key restore <name> [--encrypt]");
+            println!("                               Restore a profile from mnemonic words");
+            println!("  key list                     List all profiles");
+            println!("  key show <name>              Show the node id of a profile");
+            println!("  key export <name>            Print the secret key of a profile");
+            println!("Set IROH_PROFILE=<name> to run a node with a profile.");
+            println!("Keys are stored in {}", keys_dir()?.display());
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encrypt a new random key and parse the key file it ends up in
+    fn encrypted_key_file(passphrase: &str) -> (SecretKey, PublicKey, KeyFile) {
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let contents = encrypt(&secret_key, passphrase).unwrap();
+        let (node_id, key_file) = parse_key_file(&contents).unwrap();
+        (secret_key, node_id, key_file)
+    }
+
+    #[test]
+    fn encrypt_round_trip() {
+        let (secret_key, node_id, key_file) = encrypted_key_file("correct horse");
+        assert_eq!(node_id, secret_key.public());
+        let KeyFile::Encrypted {
+            salt,
+            nonce,
+            ciphertext,
+        } = key_file
+        else {
+            panic!("key file is not encrypted");
+        };
+        let decrypted = decrypt(&salt, &nonce, &ciphertext, "correct horse").unwrap();
+        assert_eq!(decrypted.to_bytes(), secret_key.to_bytes());
+    }
+
+    #[test]
+    fn decrypt_wrong_passphrase() {
+        let (_, _, key_file) = encrypted_key_file("correct horse");
+        let KeyFile::Encrypted {
+            salt,
+            nonce,
+            ciphertext,
+        } = key_file
+        else {
+            panic!("key file is not encrypted");
+        };
+        let err = decrypt(&salt, &nonce, &ciphertext, "battery staple").unwrap_err();
+        assert!(err.to_string().contains("wrong passphrase"));
+    }
+
+    /// The BIP39 test mnemonic, with and without the seed passphrase of the
+    /// BIP39 test vectors
+    #[test]
+    fn mnemonic_test_vector() {
+        let mnemonic = parse_mnemonic(
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+        )
+        .unwrap();
+        let secret_key = secret_key_from_mnemonic(&mnemonic, "");
+        assert_eq!(
+            hex::encode(secret_key.to_bytes()),
+            "560f9f3c94558b6551928bb781cf6092c6b8800b4fc544af2c9444ed126d51aa"
+        );
+        assert_eq!(
+            hex::encode(secret_key.public().as_bytes()),
+            "e96b1c6b8769fdb0b34fbecfdf85c33b053cecad9517e1ab88cba614335775c1"
+        );
+        let secret_key = secret_key_from_mnemonic(&mnemonic, "TREZOR");
+        assert_eq!(
+            hex::encode(secret_key.public().as_bytes()),
+            "8e07aa919abc1427adf010d10467dfba6f1f354b6707916dc9c059771ec13ecd"
+        );
+    }
+}
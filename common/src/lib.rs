@@ -0,0 +1,23 @@
+//! Modules shared by the exercises
+//!
+//! The echo wire format and the server limits are the same in every echo
+//! exercise, the versioned echo service is shared by echo3 and the node,
+//! every exercise manages identity profiles and parses its arguments the
+//! same way, clients dial and print diagnostics the same way, and access
+//! lists and the typed RPC layer work for any protocol, so they live here
+//! instead of being copied into each crate. The blobs exercises also share
+//! how files are imported and exported, behind the `blobs` feature, and the
+//! servers share their Prometheus endpoint, behind the `metrics` feature.
+pub mod access;
+pub mod cli;
+#[cfg(feature = "blobs")]
+pub mod collection;
+pub mod diag;
+pub mod dial;
+pub mod echo;
+pub mod framing;
+pub mod keys;
+pub mod limits;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod rpc;
@@ -0,0 +1,341 @@
+//! Resource limits for the echo server.
+//!
+//! Connections over the global or per node limit are closed with
+//! [`LIMIT_EXCEEDED`] and a reason. Streams over the per connection limit are
+//! reset with the same code. The bytes a node sends are throttled by a token
+//! bucket shared by all connections of that node. The bucket is kept after
+//! the node disconnects until it has refilled, so reconnecting does not reset
+//! the rate.
+//!
+//! The limits are enforced by the echo protocol handler, so they only apply
+//! to connections on the echo ALPNs. Other protocols served by the same
+//! endpoint, like the RPC, whoami and forward services of echo3, are not
+//! limited and do not count against the limits.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use iroh::{
+    endpoint::{ConnectionError, ReadError, ReadExactError, ReadToEndError, VarInt, WriteError},
+    NodeId,
+};
+use tokio::{sync::Semaphore, time::Instant};
+
+/// Application close code and stream reset code for requests over a limit
+pub const LIMIT_EXCEEDED: u32 = 429;
+
+/// Explanation for streams reset with [`LIMIT_EXCEEDED`], they carry no reason
+const STREAM_LIMIT_REASON: &str = "too many concurrent streams on this connection";
+
+/// Configurable limits, `None` means unlimited
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Maximum number of concurrent connections
+    pub max_connections: Option<usize>,
+    /// Maximum number of concurrent connections from a single node
+    pub max_connections_per_node: Option<usize>,
+    /// Maximum number of concurrent streams on a single connection
+    pub max_streams_per_connection: Option<usize>,
+    /// Maximum number of bytes per second a single node can send
+    pub bytes_per_second: Option<u64>,
+}
+
+/// A token bucket that throttles to a number of bytes per second
+///
+/// Consuming more than is available drives the bucket into debt, and the
+/// caller waits until the debt is paid off. The burst size is one second
+/// worth of bytes.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second.max(1) as f64;
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` tokens out of the bucket, waiting if there are not enough
+    pub async fn consume(&self, bytes: u64) {
+        let wait = self.take(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take `bytes` tokens out of the bucket at `now`, returns how long to wait
+    fn take(&self, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        *tokens = self.refilled(*tokens, *last, now);
+        *last = now;
+        *tokens -= bytes as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+
+    /// Whether the bucket has refilled completely at `now`
+    ///
+    /// A full bucket is no different from a new one, so it can be dropped.
+    fn is_full(&self, now: Instant) -> bool {
+        let (tokens, last) = *self.state.lock().unwrap();
+        self.refilled(tokens, last, now) >= self.rate
+    }
+
+    fn refilled(&self, tokens: f64, last: Instant, now: Instant) -> f64 {
+        let refill = now.saturating_duration_since(last).as_secs_f64() * self.rate;
+        (tokens + refill).min(self.rate)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    total: usize,
+    per_node: HashMap<NodeId, usize>,
+    /// Kept after the last connection of a node closes, until they refill,
+    /// so reconnecting does not reset the rate limit
+    buckets: HashMap<NodeId, Arc<TokenBucket>>,
+}
+
+/// Tracks connections and enforces [`Limits`]
+#[derive(Debug, Clone, Default)]
+pub struct Limiter {
+    limits: Limits,
+    state: Arc<Mutex<State>>,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            state: Default::default(),
+        }
+    }
+
+    /// Register a new connection from `node_id`, returns the reason if it is over a limit
+    ///
+    /// The connection counts against the limits until the permit is dropped.
+    pub fn try_connect(&self, node_id: NodeId) -> Result<ConnectionPermit, &'static str> {
+        let mut state = self.state.lock().unwrap();
+        if self
+            .limits
+            .max_connections
+            .is_some_and(|max| state.total >= max)
+        {
+            return Err("server is at its connection limit");
+        }
+        let count = state.per_node.get(&node_id).copied().unwrap_or_default();
+        if self
+            .limits
+            .max_connections_per_node
+            .is_some_and(|max| count >= max)
+        {
+            return Err("too many connections from this node");
+        }
+        state.total += 1;
+        *state.per_node.entry(node_id).or_default() += 1;
+        let now = Instant::now();
+        let State {
+            per_node, buckets, ..
+        } = &mut *state;
+        buckets.retain(|id, bucket| per_node.contains_key(id) || !bucket.is_full(now));
+        let bucket = self.limits.bytes_per_second.map(|rate| {
+            state
+                .buckets
+                .entry(node_id)
+                .or_insert_with(|| Arc::new(TokenBucket::new(rate)))
+                .clone()
+        });
+        let streams = self
+            .limits
+            .max_streams_per_connection
+            .map(|max| Arc::new(Semaphore::new(max)));
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            node_id,
+            bucket,
+            streams,
+        })
+    }
+}
+
+/// A connection that counts against the limits, see [`Limiter::try_connect`]
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limiter: Limiter,
+    node_id: NodeId,
+    /// Token bucket of the node, if the bytes are limited
+    pub bucket: Option<Arc<TokenBucket>>,
+    /// Available streams on this connection, if the streams are limited
+    pub streams: Option<Arc<Semaphore>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.total -= 1;
+        if let Some(count) = state.per_node.get_mut(&self.node_id) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_node.remove(&self.node_id);
+            }
+        }
+    }
+}
+
+/// If the error was caused by the remote node enforcing a limit, an explanation
+pub fn rejected_reason(err: &anyhow::Error) -> Option<String> {
+    match find_remote_close(err, LIMIT_EXCEEDED)? {
+        RemoteClose::Connection(reason) => Some(reason),
+        RemoteClose::Stream => Some(STREAM_LIMIT_REASON.to_string()),
+    }
+}
+
+/// How the remote node ended a connection or stream with an application code
+pub(crate) enum RemoteClose {
+    /// The connection was closed, with the reason the remote node gave
+    Connection(String),
+    /// A stream was reset or stopped, these carry no reason
+    Stream,
+}
+
+/// Finds a connection close, stream reset or stream stop with `code` in the
+/// error chain
+///
+/// Stream errors are also found inside the errors of [`RecvStream::read_exact`]
+/// and [`RecvStream::read_to_end`], and connection errors inside stream errors.
+///
+/// [`RecvStream::read_exact`]: iroh::endpoint::RecvStream::read_exact
+/// [`RecvStream::read_to_end`]: iroh::endpoint::RecvStream::read_to_end
+pub(crate) fn find_remote_close(err: &anyhow::Error, code: u32) -> Option<RemoteClose> {
+    let code = VarInt::from_u32(code);
+    err.chain().find_map(|cause| {
+        if let Some(err) = cause.downcast_ref::<ConnectionError>() {
+            connection_close(err, code)
+        } else if let Some(err) = cause.downcast_ref::<ReadError>() {
+            read_close(err, code)
+        } else if let Some(ReadExactError::ReadError(err)) = cause.downcast_ref() {
+            read_close(err, code)
+        } else if let Some(ReadToEndError::Read(err)) = cause.downcast_ref() {
+            read_close(err, code)
+        } else if let Some(err) = cause.downcast_ref::<WriteError>() {
+            write_close(err, code)
+        } else {
+            None
+        }
+    })
+}
+
+fn connection_close(err: &ConnectionError, code: VarInt) -> Option<RemoteClose> {
+    match err {
+        ConnectionError::ApplicationClosed(close) if close.error_code == code => Some(
+            RemoteClose::Connection(String::from_utf8_lossy(&close.reason).into_owned()),
+        ),
+        _ => None,
+    }
+}
+
+fn read_close(err: &ReadError, code: VarInt) -> Option<RemoteClose> {
+    match err {
+        ReadError::Reset(reset) if *reset == code => Some(RemoteClose::Stream),
+        ReadError::ConnectionLost(err) => connection_close(err, code),
+        _ => None,
+    }
+}
+
+fn write_close(err: &WriteError, code: VarInt) -> Option<RemoteClose> {
+    match err {
+        WriteError::Stopped(stopped) if *stopped == code => Some(RemoteClose::Stream),
+        WriteError::ConnectionLost(err) => connection_close(err, code),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+    use rand::thread_rng;
+
+    use super::*;
+
+    fn node_id() -> NodeId {
+        SecretKey::generate(&mut thread_rng()).public()
+    }
+
+    /// A bucket that was just emptied at the returned instant
+    fn empty_bucket(bytes_per_second: u64) -> (TokenBucket, Instant) {
+        let bucket = TokenBucket::new(bytes_per_second);
+        let now = Instant::now();
+        assert_eq!(bucket.take(bytes_per_second, now), Duration::ZERO);
+        (bucket, now)
+    }
+
+    #[test]
+    fn token_bucket_burst() {
+        let bucket = TokenBucket::new(100);
+        let now = Instant::now();
+        // a new bucket allows one second worth of bytes at once
+        assert_eq!(bucket.take(100, now), Duration::ZERO);
+        // anything more has to wait until the debt is paid off
+        assert_eq!(bucket.take(50, now), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn token_bucket_refill() {
+        let (bucket, start) = empty_bucket(100);
+        assert!(!bucket.is_full(start));
+        assert_eq!(
+            bucket.take(50, start + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+        assert!(bucket.is_full(start + Duration::from_millis(1500)));
+        // a long pause does not allow more than one second worth of bytes
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(150, later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn connection_caps() {
+        let limiter = Limiter::new(Limits {
+            max_connections: Some(3),
+            max_connections_per_node: Some(2),
+            ..Default::default()
+        });
+        let (a, b, c) = (node_id(), node_id(), node_id());
+        let a1 = limiter.try_connect(a).unwrap();
+        let _a2 = limiter.try_connect(a).unwrap();
+        assert!(limiter.try_connect(a).is_err());
+        let _b1 = limiter.try_connect(b).unwrap();
+        assert!(limiter.try_connect(c).is_err());
+        // closing a connection frees a slot for any node
+        drop(a1);
+        let _c1 = limiter.try_connect(c).unwrap();
+        assert!(limiter.try_connect(a).is_err());
+    }
+
+    #[test]
+    fn bucket_survives_reconnect() {
+        let limiter = Limiter::new(Limits {
+            bytes_per_second: Some(100),
+            ..Default::default()
+        });
+        let node = node_id();
+        let permit = limiter.try_connect(node).unwrap();
+        let bucket = permit.bucket.clone().unwrap();
+        assert_eq!(bucket.take(100, Instant::now()), Duration::ZERO);
+        drop(permit);
+        // the node is still in debt, so it gets the same bucket back
+        let permit = limiter.try_connect(node).unwrap();
+        assert!(Arc::ptr_eq(&bucket, permit.bucket.as_ref().unwrap()));
+    }
+}
@@ -0,0 +1,29 @@
+//! Prometheus metrics endpoint, enabled with `--metrics-addr`
+//!
+//! Serves the metrics the endpoint collects in Prometheus text format, so a
+//! long-running server can be scraped by existing monitoring. This includes
+//! the magicsock metrics (relay traffic, holepunching, direct and relayed
+//! paths), net report and port mapping metrics. Exercises add their own
+//! metrics groups to the registry they pass in.
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use iroh::Endpoint;
+use iroh_metrics::Registry;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// Spawn a HTTP server on `addr` that serves the metrics of `ep`, and of the
+/// groups already in `registry`
+pub fn serve(addr: SocketAddr, ep: &Endpoint, mut registry: Registry) -> JoinHandle<()> {
+    registry.register_all(ep.metrics());
+    println!("Serving metrics at http://{}/metrics", addr);
+    tokio::spawn(async move {
+        let registry = Arc::new(RwLock::new(registry));
+        if let Err(cause) = iroh_metrics::service::start_metrics_server(addr, registry).await {
+            warn!("Metrics server failed: {:?}", cause);
+        }
+    })
+}
@@ -0,0 +1,234 @@
+//! A small typed request/response layer on top of iroh connections.
+//!
+//! A service defines its request and response types and implements
+//! [`Service`]. [`RpcProtocol`] serves it as a protocol handler, and
+//! [`RpcClient`] calls it from the other side.
+//!
+//! Every request is sent on its own bi-directional stream. The request is
+//! postcard encoded and the send side is finished, then the server replies
+//! with a postcard encoded `Result<Response, RemoteError>` and finishes.
+use std::{fmt, future::Future, marker::PhantomData, pin::Pin, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use iroh::{
+    endpoint::{Connection, ConnectionError, ReadToEndError, RecvStream, SendStream},
+    protocol::ProtocolHandler,
+    NodeId,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+/// Default maximum size of an encoded request or response
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Default time a request may take, on both the client and the server
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A typed request/response service
+pub trait Service: fmt::Debug + Send + Sync + 'static {
+    type Request: Serialize + DeserializeOwned + fmt::Debug + Send + 'static;
+    type Response: Serialize + DeserializeOwned + fmt::Debug + Send + 'static;
+
+    /// Handle a single request from `remote`
+    fn handle(
+        &self,
+        remote: NodeId,
+        request: Self::Request,
+    ) -> impl Future<Output = Result<Self::Response, RemoteError>> + Send;
+}
+
+/// What went wrong on the remote side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    /// The request could not be decoded
+    InvalidRequest,
+    /// The request exceeded the maximum message size
+    TooLarge,
+    /// The request took longer than the server allows
+    Timeout,
+    /// The service rejected the request
+    Service,
+}
+
+/// An error reported by the server, sent back to the client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl RemoteError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// An error rejecting a request for service specific reasons
+    pub fn service(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Service, message)
+    }
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "remote error ({:?}): {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+/// Protocol handler serving a [`Service`]
+#[derive(Debug)]
+pub struct RpcProtocol<S> {
+    service: Arc<S>,
+    max_message_size: usize,
+    timeout: Duration,
+}
+
+impl<S> Clone for RpcProtocol<S> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            max_message_size: self.max_message_size,
+            timeout: self.timeout,
+        }
+    }
+}
+
+impl<S: Service> RpcProtocol<S> {
+    pub fn new(service: S) -> Self {
+        Self {
+            service: Arc::new(service),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Set the time a single request may take
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Handle a single request stream
+    async fn serve_request(
+        self,
+        remote: NodeId,
+        mut send_stream: SendStream,
+        mut recv_stream: RecvStream,
+    ) -> Result<()> {
+        let response = match recv_stream.read_to_end(self.max_message_size).await {
+            Ok(bytes) => match postcard::from_bytes::<S::Request>(&bytes) {
+                Ok(request) => {
+                    info!("Request from {}: {:?}", remote.fmt_short(), request);
+                    match tokio::time::timeout(self.timeout, self.service.handle(remote, request))
+                        .await
+                    {
+                        Ok(response) => response,
+                        Err(_) => Err(RemoteError::new(
+                            ErrorKind::Timeout,
+                            format!("request took longer than {:?}", self.timeout),
+                        )),
+                    }
+                }
+                Err(cause) => Err(RemoteError::new(
+                    ErrorKind::InvalidRequest,
+                    cause.to_string(),
+                )),
+            },
+            Err(ReadToEndError::TooLong) => Err(RemoteError::new(
+                ErrorKind::TooLarge,
+                format!("request exceeds {} bytes", self.max_message_size),
+            )),
+            Err(cause) => return Err(cause.into()),
+        };
+        let bytes = postcard::to_allocvec(&response)?;
+        send_stream.write_all(&bytes).await?;
+        send_stream.finish()?;
+        Ok(())
+    }
+}
+
+impl<S: Service> ProtocolHandler for RpcProtocol<S> {
+    fn accept(
+        &self,
+        conn: Connection,
+    ) -> Pin<Box<dyn std::future::Future<Output = Result<()>> + Send + 'static>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let remote = conn.remote_node_id()?;
+            info!("RPC connection accepted from {}", remote);
+
+            // Every request comes in on its own stream
+            let mut tasks = JoinSet::new();
+            let res: Result<()> = loop {
+                let (send_stream, recv_stream) = match conn.accept_bi().await {
+                    Ok(streams) => streams,
+                    Err(ConnectionError::ApplicationClosed(_)) => break Ok(()),
+                    Err(cause) => break Err(cause.into()),
+                };
+                let this = this.clone();
+                tasks.spawn(async move {
+                    if let Err(cause) = this.serve_request(remote, send_stream, recv_stream).await {
+                        warn!("Failed to serve request: {:?}", cause);
+                    }
+                });
+            };
+
+            // Let in-flight requests finish, also if the connection failed
+            while tasks.join_next().await.is_some() {}
+            info!("RPC connection closed");
+
+            res
+        })
+    }
+}
+
+/// Client stub for a [`Service`] on a connection
+#[derive(Debug, Clone)]
+pub struct RpcClient<S> {
+    conn: Connection,
+    max_message_size: usize,
+    timeout: Duration,
+    _service: PhantomData<fn() -> S>,
+}
+
+impl<S: Service> RpcClient<S> {
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            timeout: DEFAULT_TIMEOUT,
+            _service: PhantomData,
+        }
+    }
+
+    /// Set the time to wait for a response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send a request on a new stream and wait for the response
+    ///
+    /// Errors reported by the server can be downcast to [`RemoteError`].
+    pub async fn call(&self, request: S::Request) -> Result<S::Response> {
+        let call = async {
+            let (mut send_stream, mut recv_stream) = self.conn.open_bi().await?;
+            let bytes = postcard::to_allocvec(&request)?;
+            send_stream.write_all(&bytes).await?;
+            send_stream.finish()?;
+
+            let bytes = recv_stream.read_to_end(self.max_message_size).await?;
+            let response: Result<S::Response, RemoteError> =
+                postcard::from_bytes(&bytes).context("invalid response")?;
+            Ok(response?)
+        };
+        tokio::time::timeout(self.timeout, call)
+            .await
+            .with_context(|| format!("no response within {:?}", self.timeout))?
+    }
+}
@@ -1,23 +1,25 @@
 use std::{env, process, str::FromStr};
 
 use anyhow::{Context, Result};
+use common::{
+    cli,
+    framing::{self, ECHO_ALPN, LEGACY_ECHO_ALPN},
+    keys,
+};
 use iroh::Endpoint;
 use iroh_base::ticket::NodeTicket;
 use tracing::info;
 
-mod util;
-
-/// The ALPN protocol identifier for the echo service
-const ECHO_ALPN: &[u8] = b"ECHO";
-
 /// Server mode - accepts connections and echoes messages back
-async fn accept() -> Result<()> {
+///
+/// Serves both the framed and the legacy wire format, each on its own ALPN.
+async fn accept(max_message_size: usize) -> Result<()> {
     // Get or generate a secret key
-    let secret_key = util::get_or_generate_secret_key()?;
+    let secret_key = keys::get_or_generate_secret_key()?;
 
     // Create an endpoint and print the node ID
     let ep = Endpoint::builder()
-        .alpns(vec![ECHO_ALPN.to_vec()])
+        .alpns(vec![ECHO_ALPN.to_vec(), LEGACY_ECHO_ALPN.to_vec()])
         .secret_key(secret_key)
         .bind()
         .await?;
@@ -34,7 +36,7 @@ async fn accept() -> Result<()> {
         env::args().next().unwrap_or_default(),
         ticket
     );
-    println!("inspect ticket at https://ticket.iroh.computer/\n");
+    println!("inspect ticket with: cargo run -p sendme4 ticket inspect <ticket>\n");
 
     // Accept a connection
     info!("Waiting for connections");
@@ -43,15 +45,14 @@ async fn accept() -> Result<()> {
     info!("Connection accepted");
 
     // Accept a bi-directional stream
-    let (mut send_stream, mut recv_stream) = conn.accept_bi().await?;
+    let (send_stream, recv_stream) = conn.accept_bi().await?;
 
-    // Read the message
-    let msg = recv_stream.read_to_end(1024).await?;
-    info!("Received message: {}", String::from_utf8_lossy(&msg));
-
-    // Echo the message back
-    send_stream.write_all(&msg).await?;
-    send_stream.finish()?;
+    // Echo everything received on the stream back, in the format of the ALPN
+    if conn.alpn().as_deref() == Some(LEGACY_ECHO_ALPN) {
+        framing::serve_legacy_stream(send_stream, recv_stream, max_message_size, None).await?;
+    } else {
+        framing::serve_stream(send_stream, recv_stream, max_message_size, None).await?;
+    }
 
     // Wait for the client to close the connection
     conn.closed().await;
@@ -64,7 +65,19 @@ async fn accept() -> Result<()> {
 }
 
 /// Client mode - connects to a server and sends a message
-async fn connect(message: &str, ticket: &str) -> Result<()> {
+///
+/// In streaming mode, the message is streamed to the server and the echo is
+/// read as it arrives, without a size limit. In legacy mode, the message is
+/// sent in the original wire format, for servers that only speak that.
+async fn connect(
+    message: &str,
+    ticket: &str,
+    max_message_size: usize,
+    streaming: bool,
+    legacy: bool,
+) -> Result<()> {
+    anyhow::ensure!(!(streaming && legacy), "the legacy format can not stream");
+
     // Parse the address using NodeTicket
     let ticket = NodeTicket::from_str(ticket).context("invalid address")?;
 
@@ -74,19 +87,21 @@ async fn connect(message: &str, ticket: &str) -> Result<()> {
     let ep = Endpoint::builder().bind().await?;
 
     // Connect to the node
-    let conn = ep.connect(ticket, ECHO_ALPN).await?;
+    let alpn = if legacy { LEGACY_ECHO_ALPN } else { ECHO_ALPN };
+    let conn = ep.connect(ticket, alpn).await?;
     info!("Connected");
 
-    // Open a bi-directional stream
-    let (mut send_stream, mut recv_stream) = conn.open_bi().await?;
-
-    // Send the message
+    // Send the message and wait for the response
     info!("Sending message: {}", message);
-    send_stream.write_all(message.as_bytes()).await?;
-    send_stream.finish()?;
-
-    // Wait for the response
-    let res = recv_stream.read_to_end(1024).await?;
+    let res = if legacy {
+        framing::send_legacy_message(&conn, message.as_bytes(), max_message_size).await?
+    } else if streaming {
+        let mut res = Vec::new();
+        framing::stream_through(&conn, message.as_bytes(), &mut res).await?;
+        res
+    } else {
+        framing::send_message(&conn, message.as_bytes(), max_message_size).await?
+    };
     println!("Received response: {}", String::from_utf8_lossy(&res));
 
     // Close the connection
@@ -107,24 +122,40 @@ async fn main() -> Result<()> {
     // Initialize tracing with default configuration
     tracing_subscriber::fmt::init();
 
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let max_message_size = cli::take_option(&mut args, "--max-size")?
+        .map(|size| size.parse::<usize>())
+        .transpose()
+        .context("invalid --max-size")?
+        .unwrap_or(framing::DEFAULT_MAX_MESSAGE_SIZE);
+    let streaming = cli::take_flag(&mut args, "--stream");
+    let legacy = cli::take_flag(&mut args, "--legacy");
     let cmd = args.get(1).map(|x| x.to_lowercase()).unwrap_or_default();
     match cmd.as_str() {
         "accept" if args.len() == 2 => {
             // Server mode - accept connections
-            accept().await
+            accept(max_message_size).await
         }
         "connect" if args.len() == 4 => {
             // Client mode - connect to a server and send a message
             let message = &args[2];
             let ticket = &args[3];
-            connect(message, ticket).await
+            connect(message, ticket, max_message_size, streaming, legacy).await
+        }
+        "key" => {
+            // Manage named identity profiles
+            keys::key_command(&args[2..])
         }
         _ => {
             println!("Usage: echo1 <command> [args]");
             println!("Commands:");
             println!("  accept                       Listen for echo requests");
             println!("  connect <message> <ticket>   Connect to an echo server and send a message");
+            println!("  key <command>                Manage identity profiles");
+            println!("Options:");
+            println!("  --max-size <bytes>           Maximum size of a framed message");
+            println!("  --stream                     Stream the message instead of framing it");
+            println!("  --legacy                     Send the message in the original format");
             process::exit(1);
         }
     }
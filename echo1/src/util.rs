@@ -1,21 +0,0 @@
-//# Small utility functions that don't have anything to do with the exercise itself.
-use std::{env, str::FromStr};
-
-use anyhow::{Context, Result};
-use iroh_base::SecretKey;
-use rand::thread_rng;
-
-/// Gets a secret key from the IROH_SECRET environment variable or generates a new random one.
-/// If the environment variable is set, it must be a valid string representation of a secret key.
-pub fn get_or_generate_secret_key() -> Result<SecretKey> {
-    if let Ok(secret) = env::var("IROH_SECRET") {
-        // Parse the secret key from string
-        SecretKey::from_str(&secret).context("Invalid secret key format")
-    } else {
-        // Generate a new random key
-        let secret_key = SecretKey::generate(&mut thread_rng());
-        println!("Generated new secret key: {}", secret_key);
-        println!("To reuse this key, set the IROH_SECRET environment variable to this value");
-        Ok(secret_key)
-    }
-}
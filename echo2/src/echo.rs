@@ -1,40 +1,81 @@
 use std::pin::Pin;
 
 use anyhow::Result;
-use iroh::{endpoint::Connection, protocol::ProtocolHandler};
-use tracing::info;
-
-/// The ALPN protocol identifier for the echo service
-pub const ECHO_ALPN: &[u8] = b"ECHO";
+use common::framing::{self, LEGACY_ECHO_ALPN};
+use iroh::{
+    endpoint::{Connection, ConnectionError},
+    protocol::ProtocolHandler,
+};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
 
 /// Echo protocol handler
-#[derive(Debug)]
-pub struct EchoProtocol;
+///
+/// Register it for both [`framing::ECHO_ALPN`] and [`LEGACY_ECHO_ALPN`], it
+/// speaks the wire format of the ALPN a connection was accepted on.
+#[derive(Debug, Clone)]
+pub struct EchoProtocol {
+    /// Maximum size of a single framed message
+    max_message_size: usize,
+}
+
+impl Default for EchoProtocol {
+    fn default() -> Self {
+        Self::new(framing::DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+impl EchoProtocol {
+    /// Create a new echo protocol handler with the given maximum message size
+    pub fn new(max_message_size: usize) -> Self {
+        Self { max_message_size }
+    }
+}
 
 impl ProtocolHandler for EchoProtocol {
     fn accept(
         &self,
         conn: Connection,
     ) -> Pin<Box<dyn std::future::Future<Output = Result<()>> + Send + 'static>> {
+        let max_message_size = self.max_message_size;
         Box::pin(async move {
-            info!("Connection accepted");
-
-            // Accept a bi-directional stream
-            let (mut send_stream, mut recv_stream) = conn.accept_bi().await?;
-
-            // Read the message
-            let msg = recv_stream.read_to_end(1024).await?;
-            info!("Received message: {}", String::from_utf8_lossy(&msg));
+            let remote = conn.remote_node_id()?;
+            let legacy = conn.alpn().as_deref() == Some(LEGACY_ECHO_ALPN);
+            info!("Connection accepted from {}, legacy: {}", remote, legacy);
 
-            // Echo the message back
-            send_stream.write_all(&msg).await?;
-            send_stream.finish()?;
+            // Accept bi-directional streams until the client closes the connection,
+            // and echo each of them in its own task
+            let mut tasks = JoinSet::new();
+            let res: Result<()> = loop {
+                let (send_stream, recv_stream) = match conn.accept_bi().await {
+                    Ok(streams) => streams,
+                    Err(ConnectionError::ApplicationClosed(_)) => break Ok(()),
+                    Err(cause) => break Err(cause.into()),
+                };
+                tasks.spawn(async move {
+                    let res = if legacy {
+                        framing::serve_legacy_stream(
+                            send_stream,
+                            recv_stream,
+                            max_message_size,
+                            None,
+                        )
+                        .await
+                    } else {
+                        framing::serve_stream(send_stream, recv_stream, max_message_size, None)
+                            .await
+                    };
+                    if let Err(cause) = res {
+                        warn!("Failed to echo stream: {:?}", cause);
+                    }
+                });
+            };
 
-            // Wait for the client to close the connection
-            conn.closed().await;
+            // Let in-flight streams finish, also if the connection failed
+            while tasks.join_next().await.is_some() {}
             info!("Connection closed");
 
-            Ok(())
+            res
         })
     }
 }
@@ -1,22 +1,28 @@
 use std::{env, process, str::FromStr};
 
 use anyhow::{Context, Result};
+use common::{
+    cli,
+    framing::{self, ECHO_ALPN, LEGACY_ECHO_ALPN},
+    keys,
+};
 use iroh::{protocol::Router, Endpoint};
 use iroh_base::ticket::NodeTicket;
-use tokio::signal;
+use tokio::{signal, task::JoinSet};
 use tracing::info;
 
 mod echo;
-mod util;
 
 /// Server mode - accepts connections and echoes messages back
-async fn accept() -> Result<()> {
+///
+/// Serves both the framed and the legacy wire format, each on its own ALPN.
+async fn accept(max_message_size: usize) -> Result<()> {
     // Get or generate a secret key
-    let secret_key = util::get_or_generate_secret_key()?;
+    let secret_key = keys::get_or_generate_secret_key()?;
 
     // Create an endpoint and print the node ID
     let ep = Endpoint::builder()
-        .alpns(vec![echo::ECHO_ALPN.to_vec()])
+        .alpns(vec![ECHO_ALPN.to_vec(), LEGACY_ECHO_ALPN.to_vec()])
         .secret_key(secret_key)
         .bind()
         .await?;
@@ -33,11 +39,13 @@ async fn accept() -> Result<()> {
         env::args().next().unwrap_or_default(),
         ticket
     );
-    println!("inspect ticket at https://ticket.iroh.computer/\n");
+    println!("inspect ticket with: cargo run -p sendme4 ticket inspect <ticket>\n");
 
     // Create a router with the endpoint
+    let echo = echo::EchoProtocol::new(max_message_size);
     let router = Router::builder(ep)
-        .accept(echo::ECHO_ALPN, echo::EchoProtocol)
+        .accept(ECHO_ALPN, echo.clone())
+        .accept(LEGACY_ECHO_ALPN, echo)
         .spawn();
 
     println!("Server is running. Press Ctrl+C to stop...");
@@ -52,8 +60,21 @@ async fn accept() -> Result<()> {
     Ok(())
 }
 
-/// Client mode - connects to a server and sends a message
-async fn connect(message: &str, ticket: &str) -> Result<()> {
+/// Client mode - connects to a server and sends one or more messages
+///
+/// Every message is sent on its own bi-directional stream, and all streams
+/// share a single connection. In streaming mode without messages, stdin is
+/// streamed to the server and the echo is written to stdout. In legacy mode,
+/// the messages are sent in the original wire format.
+async fn connect(
+    messages: &[String],
+    ticket: &str,
+    max_message_size: usize,
+    streaming: bool,
+    legacy: bool,
+) -> Result<()> {
+    anyhow::ensure!(!(streaming && legacy), "the legacy format can not stream");
+
     // Parse the address using NodeTicket
     let ticket = NodeTicket::from_str(ticket).context("invalid address")?;
 
@@ -63,20 +84,46 @@ async fn connect(message: &str, ticket: &str) -> Result<()> {
     let ep = Endpoint::builder().bind().await?;
 
     // Connect to the node
-    let conn = ep.connect(ticket, echo::ECHO_ALPN).await?;
+    let alpn = if legacy { LEGACY_ECHO_ALPN } else { ECHO_ALPN };
+    let conn = ep.connect(ticket, alpn).await?;
     info!("Connected");
 
-    // Open a bi-directional stream
-    let (mut send_stream, mut recv_stream) = conn.open_bi().await?;
-
-    // Send the message
-    info!("Sending message: {}", message);
-    send_stream.write_all(message.as_bytes()).await?;
-    send_stream.finish()?;
+    if streaming && messages.is_empty() {
+        // Stream stdin through the echo service to stdout
+        let (sent, received) =
+            framing::stream_through(&conn, tokio::io::stdin(), tokio::io::stdout()).await?;
+        info!("Sent {} bytes, received {} bytes", sent, received);
+    } else {
+        // Send all messages concurrently, each on its own stream
+        let mut tasks = JoinSet::new();
+        for (i, message) in messages.iter().cloned().enumerate() {
+            let conn = conn.clone();
+            tasks.spawn(async move {
+                info!("Sending message: {}", message);
+                let res = if legacy {
+                    framing::send_legacy_message(&conn, message.as_bytes(), max_message_size)
+                        .await?
+                } else if streaming {
+                    let mut res = Vec::new();
+                    framing::stream_through(&conn, message.as_bytes(), &mut res).await?;
+                    res
+                } else {
+                    framing::send_message(&conn, message.as_bytes(), max_message_size).await?
+                };
+                anyhow::Ok((i, res))
+            });
+        }
 
-    // Wait for the response
-    let res = recv_stream.read_to_end(1024).await?;
-    println!("Received response: {}", String::from_utf8_lossy(&res));
+        // Print the responses in the order the messages were given
+        let mut responses = Vec::with_capacity(messages.len());
+        while let Some(res) = tasks.join_next().await {
+            responses.push(res??);
+        }
+        responses.sort_by_key(|(i, _)| *i);
+        for (_, res) in responses {
+            println!("Received response: {}", String::from_utf8_lossy(&res));
+        }
+    }
 
     // Close the connection
     conn.close(0u8.into(), b"done");
@@ -96,24 +143,44 @@ async fn main() -> Result<()> {
     // Initialize tracing with default configuration
     tracing_subscriber::fmt::init();
 
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let max_message_size = cli::take_option(&mut args, "--max-size")?
+        .map(|size| size.parse::<usize>())
+        .transpose()
+        .context("invalid --max-size")?
+        .unwrap_or(framing::DEFAULT_MAX_MESSAGE_SIZE);
+    let streaming = cli::take_flag(&mut args, "--stream");
+    let legacy = cli::take_flag(&mut args, "--legacy");
     let cmd = args.get(1).map(|x| x.to_lowercase()).unwrap_or_default();
     match cmd.as_str() {
         "accept" if args.len() == 2 => {
             // server mode - accept connections
-            accept().await
+            accept(max_message_size).await
+        }
+        "connect" if args.len() >= 4 || (streaming && args.len() == 3) => {
+            // Client mode - connect to a server and send messages
+            let (ticket, messages) = args[2..].split_last().unwrap();
+            connect(messages, ticket, max_message_size, streaming, legacy).await
         }
-        "connect" if args.len() == 4 => {
-            // Client mode - connect to a server and send a message
-            let message = &args[2];
-            let ticket = &args[3];
-            connect(message, ticket).await
+        "key" => {
+            // Manage named identity profiles
+            keys::key_command(&args[2..])
         }
         _ => {
             println!("Usage: echo1 <command> [args]");
             println!("Commands:");
-            println!("  accept                       Listen for echo requests");
-            println!("  connect <message> <ticket>   Connect to an echo server and send a message");
+            println!("  accept                         Listen for echo requests");
+            println!(
+                "  connect <message>... <ticket>  Connect to an echo server and send messages"
+            );
+            println!("  connect --stream [<message>...] <ticket>");
+            println!(
+                "                                 Stream messages, or stdin if none are given"
+            );
+            println!("  key <command>                  Manage identity profiles");
+            println!("Options:");
+            println!("  --max-size <bytes>             Maximum size of a framed message");
+            println!("  --legacy                       Send the messages in the original format");
             process::exit(1);
         }
     }
@@ -0,0 +1,196 @@
+//! A static address book for node discovery, backed by a local file.
+//!
+//! The file maps node ids to a relay URL and direct addresses. TOML is used
+//! unless the file name ends in `.json`:
+//!
+//! ```toml
+//! [nodes.ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b6]
+//! relay = "https://euw1-1.relay.iroh.network./"
+//! addrs = ["192.168.1.20:4433"]
+//! ```
+//!
+//! The file is reloaded when it changes. If recording is enabled, the
+//! addresses of nodes we successfully connected to are written back to it.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use iroh::{
+    discovery::{Discovery, DiscoveryItem, NodeInfo},
+    Endpoint, NodeAddr, NodeId, RelayUrl,
+};
+use n0_future::{boxed::BoxStream, stream};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Provenance reported for addresses that come from the address book
+const PROVENANCE: &str = "address-book";
+
+/// How often the address book file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The known addresses of a single node
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    relay: Option<RelayUrl>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    addrs: BTreeSet<SocketAddr>,
+}
+
+/// The contents of an address book file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BookFile {
+    #[serde(default)]
+    nodes: BTreeMap<String, Entry>,
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// Read an address book file, a missing file is an empty address book
+fn read_file(path: &Path) -> Result<BTreeMap<NodeId, Entry>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(cause) => {
+            return Err(cause).with_context(|| format!("failed to read {}", path.display()))
+        }
+    };
+    let file: BookFile = if is_json(path) {
+        serde_json::from_str(&contents)?
+    } else {
+        toml::from_str(&contents)?
+    };
+    file.nodes
+        .into_iter()
+        .map(|(node_id, entry)| {
+            let node_id = node_id
+                .parse()
+                .with_context(|| format!("invalid node id {}", node_id))?;
+            Ok((node_id, entry))
+        })
+        .collect::<Result<_>>()
+        .with_context(|| format!("invalid address book {}", path.display()))
+}
+
+/// Write an address book file, replacing the old one atomically
+fn write_file(path: &Path, nodes: &BTreeMap<NodeId, Entry>) -> Result<()> {
+    let file = BookFile {
+        nodes: nodes
+            .iter()
+            .map(|(node_id, entry)| (node_id.to_string(), entry.clone()))
+            .collect(),
+    };
+    let contents = if is_json(path) {
+        serde_json::to_string_pretty(&file)?
+    } else {
+        toml::to_string_pretty(&file)?
+    };
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents).with_context(|| format!("failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// A [`Discovery`] service that resolves node ids from an address book file
+#[derive(Debug, Clone)]
+pub struct AddressBook {
+    path: PathBuf,
+    record: bool,
+    nodes: Arc<RwLock<BTreeMap<NodeId, Entry>>>,
+}
+
+impl AddressBook {
+    /// Load the address book from a file
+    ///
+    /// If `record` is set, addresses passed to [`AddressBook::record`] are
+    /// saved to the file, which is created if it does not exist.
+    pub fn load(path: impl Into<PathBuf>, record: bool) -> Result<Self> {
+        let path = path.into();
+        let nodes = read_file(&path)?;
+        info!("Loaded {} nodes from {}", nodes.len(), path.display());
+        Ok(Self {
+            path,
+            record,
+            nodes: Arc::new(RwLock::new(nodes)),
+        })
+    }
+
+    /// Reload the address book from its file
+    ///
+    /// If the file is invalid, the previous entries stay in effect.
+    pub fn reload(&self) -> Result<()> {
+        let nodes = read_file(&self.path)?;
+        *self.nodes.write().unwrap() = nodes;
+        Ok(())
+    }
+
+    /// Spawn a task that reloads the address book whenever the file changes
+    pub fn spawn_reload_task(&self) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let modified =
+                |path: &Path| -> Option<SystemTime> { path.metadata().ok()?.modified().ok() };
+            let mut last_modified = modified(&this.path);
+            loop {
+                tokio::time::sleep(RELOAD_INTERVAL).await;
+                let current = modified(&this.path);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                match this.reload() {
+                    Ok(()) => info!("Reloaded address book {}", this.path.display()),
+                    Err(cause) => warn!("Keeping previous address book: {:?}", cause),
+                }
+            }
+        })
+    }
+
+    /// Save the addresses of a node, if recording is enabled
+    ///
+    /// The new addresses replace the old ones of that node. The file is only
+    /// written if anything changed.
+    pub fn record(&self, addr: &NodeAddr) -> Result<()> {
+        if !self.record {
+            return Ok(());
+        }
+        let entry = Entry {
+            relay: addr.relay_url.clone(),
+            addrs: addr.direct_addresses.clone(),
+        };
+        let mut nodes = self.nodes.write().unwrap();
+        if nodes.get(&addr.node_id) == Some(&entry) {
+            return Ok(());
+        }
+        nodes.insert(addr.node_id, entry);
+        write_file(&self.path, &nodes)?;
+        info!(
+            "Recorded addresses of {} in the address book",
+            addr.node_id.fmt_short()
+        );
+        Ok(())
+    }
+}
+
+impl Discovery for AddressBook {
+    fn resolve(
+        &self,
+        _endpoint: Endpoint,
+        node_id: NodeId,
+    ) -> Option<BoxStream<Result<DiscoveryItem>>> {
+        let entry = self.nodes.read().unwrap().get(&node_id)?.clone();
+        let node_info = NodeInfo::new(node_id)
+            .with_relay_url(entry.relay)
+            .with_direct_addresses(entry.addrs);
+        let item = DiscoveryItem::new(node_info, PROVENANCE, None);
+        Some(Box::pin(stream::once(Ok(item))))
+    }
+}
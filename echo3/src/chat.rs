@@ -0,0 +1,145 @@
+//! Interactive full-duplex chat between two nodes
+//!
+//! Each side sends the lines it reads from stdin on its own uni-directional
+//! stream, and prints the lines it receives from the peer. Either side can
+//! leave by closing stdin (Ctrl+D) or pressing Ctrl+C.
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use iroh::{
+    endpoint::{Connection, ConnectionError},
+    protocol::ProtocolHandler,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::{mpsc, oneshot},
+};
+use tracing::info;
+
+/// The ALPN protocol identifier for the chat service
+pub const CHAT_ALPN: &[u8] = b"ECHO-CHAT";
+
+/// How long to wait for the peer to receive the last lines before leaving
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Chat protocol handler
+///
+/// Hands the first incoming connection to the interactive session, and
+/// rejects all further connections as busy.
+#[derive(Debug, Clone)]
+pub struct ChatProtocol {
+    /// Taken by the first connection
+    sender: Arc<Mutex<Option<oneshot::Sender<Connection>>>>,
+}
+
+impl ChatProtocol {
+    /// Create a new chat protocol handler that sends the first accepted connection to `sender`
+    pub fn new(sender: oneshot::Sender<Connection>) -> Self {
+        Self {
+            sender: Arc::new(Mutex::new(Some(sender))),
+        }
+    }
+}
+
+impl ProtocolHandler for ChatProtocol {
+    fn accept(
+        &self,
+        conn: Connection,
+    ) -> Pin<Box<dyn std::future::Future<Output = Result<()>> + Send + 'static>> {
+        let sender = self.sender.clone();
+        Box::pin(async move {
+            let remote = conn.remote_node_id()?;
+            let sender = sender.lock().unwrap().take();
+            let res = match sender {
+                Some(sender) => sender.send(conn),
+                None => Err(conn),
+            };
+            if let Err(conn) = res {
+                info!("Rejecting chat from {}, already chatting", remote);
+                conn.close(1u8.into(), b"busy");
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Read lines from stdin on a separate thread
+///
+/// A blocking read on stdin would otherwise keep the runtime alive after the
+/// peer has left.
+pub fn stdin_lines() -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel(16);
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Run an interactive chat session over a connection until either side leaves
+pub async fn run(conn: Connection) -> Result<()> {
+    let remote = conn.remote_node_id()?;
+    let peer = remote.fmt_short();
+    println!(
+        "Chatting with {}. Type a line and press enter to send, Ctrl+D to leave.",
+        remote
+    );
+
+    let send = async {
+        let mut send_stream = conn.open_uni().await?;
+        let mut lines = stdin_lines();
+        while let Some(line) = lines.recv().await {
+            send_stream.write_all(line.as_bytes()).await?;
+            send_stream.write_all(b"\n").await?;
+        }
+        send_stream.finish()?;
+        // Closing the connection discards data the peer has not acknowledged yet
+        let _ = tokio::time::timeout(FLUSH_TIMEOUT, send_stream.stopped()).await;
+        anyhow::Ok(())
+    };
+    let recv = async {
+        let recv_stream = conn.accept_uni().await?;
+        let mut lines = BufReader::new(recv_stream).lines();
+        while let Some(line) = lines.next_line().await? {
+            println!("{}: {}", peer, line);
+        }
+        anyhow::Ok(())
+    };
+
+    let res = tokio::select! {
+        res = send => res.map(|()| println!("Leaving the chat")),
+        res = recv => res.map(|()| println!("{} left the chat", peer)),
+        _ = tokio::signal::ctrl_c() => {
+            println!("Leaving the chat");
+            Ok(())
+        }
+    };
+    match res {
+        Ok(()) => {
+            conn.close(0u8.into(), b"bye");
+            Ok(())
+        }
+        // Errors caused by the peer closing the connection are a normal way to leave
+        Err(cause) => match conn.close_reason() {
+            Some(ConnectionError::ApplicationClosed(close)) => {
+                println!(
+                    "{} left the chat: {}",
+                    peer,
+                    String::from_utf8_lossy(&close.reason)
+                );
+                Ok(())
+            }
+            _ => Err(cause),
+        },
+    }
+}
@@ -0,0 +1,60 @@
+//! The echo service, implemented on top of the typed RPC layer
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use common::rpc::{RemoteError, RpcClient, Service};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+
+/// The ALPN protocol identifier for the echo RPC service
+pub const ECHO_RPC_ALPN: &[u8] = b"ECHO-RPC";
+
+/// Requests of the echo RPC service
+#[derive(Debug, Serialize, Deserialize)]
+pub enum EchoRequest {
+    /// Echo a message back
+    Echo(String),
+    /// Echo a message back, reversed
+    Reverse(String),
+    /// Ask how many requests the server has handled
+    Count,
+}
+
+/// Responses of the echo RPC service, one for each request
+#[derive(Debug, Serialize, Deserialize)]
+pub enum EchoResponse {
+    Echo(String),
+    Reverse(String),
+    Count(u64),
+}
+
+/// Echo RPC service
+#[derive(Debug, Default)]
+pub struct EchoService {
+    count: AtomicU64,
+}
+
+/// Client stub for the echo RPC service
+pub type EchoClient = RpcClient<EchoService>;
+
+impl Service for EchoService {
+    type Request = EchoRequest;
+    type Response = EchoResponse;
+
+    async fn handle(
+        &self,
+        _remote: NodeId,
+        request: EchoRequest,
+    ) -> Result<EchoResponse, RemoteError> {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        match request {
+            EchoRequest::Echo(message) | EchoRequest::Reverse(message) if message.is_empty() => {
+                Err(RemoteError::service("message must not be empty"))
+            }
+            EchoRequest::Echo(message) => Ok(EchoResponse::Echo(message)),
+            EchoRequest::Reverse(message) => {
+                Ok(EchoResponse::Reverse(message.chars().rev().collect()))
+            }
+            EchoRequest::Count => Ok(EchoResponse::Count(count)),
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Port forwarding over iroh connections
+//!
+//! The listening side accepts TCP or Unix domain socket connections locally
+//! and tunnels each of them to the remote node over its own bi-directional
+//! stream. The serving side connects every stream to a fixed target, so a
+//! client can only reach the service the server chose to expose.
+//!
+//! Every stream starts with a single [`HELLO`] byte, so the stream becomes
+//! visible to the server even for protocols where the server speaks first.
+//!
+//! Pipe mode uses the same streams to connect stdin and stdout of two
+//! processes, netcat-style, under its own ALPN.
+use std::{fmt, path::PathBuf, pin::Pin, sync::Arc};
+
+use anyhow::{ensure, Result};
+use iroh::{
+    endpoint::{Connection, ConnectionError, RecvStream, SendStream},
+    protocol::ProtocolHandler,
+};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+use tracing::{info, warn};
+
+/// The ALPN protocol identifier for the forwarding service
+pub const FORWARD_ALPN: &[u8] = b"ECHO-FORWARD";
+
+/// The ALPN protocol identifier for pipe mode
+pub const PIPE_ALPN: &[u8] = b"ECHO-PIPE";
+
+/// The first byte of every forwarded stream
+const HELLO: u8 = 0;
+
+/// A local address to connect to or to listen on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// A TCP `host:port`
+    Tcp(String),
+    /// A Unix domain socket, written as `unix:<path>`
+    Unix(PathBuf),
+}
+
+impl Address {
+    pub fn parse(addr: &str) -> Self {
+        match addr.strip_prefix("unix:") {
+            Some(path) => Self::Unix(path.into()),
+            None => Self::Tcp(addr.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Open a stream and send the hello byte
+pub async fn open_stream(conn: &Connection) -> Result<(SendStream, RecvStream)> {
+    let (mut send_stream, recv_stream) = conn.open_bi().await?;
+    send_stream.write_all(&[HELLO]).await?;
+    Ok((send_stream, recv_stream))
+}
+
+/// Read the hello byte from a stream opened with [`open_stream`]
+pub async fn read_hello(recv_stream: &mut RecvStream) -> Result<()> {
+    let mut hello = [0u8; 1];
+    recv_stream.read_exact(&mut hello).await?;
+    ensure!(hello[0] == HELLO, "unexpected hello byte {}", hello[0]);
+    Ok(())
+}
+
+/// Copy bytes in both directions until both sides are done
+///
+/// Only returns once the peer has read everything we sent, so the
+/// connection can be closed right after. Returns the number of bytes sent
+/// and received.
+pub async fn pipe(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    mut send_stream: SendStream,
+    mut recv_stream: RecvStream,
+) -> Result<(u64, u64)> {
+    // Both directions must run concurrently, otherwise both sides block as
+    // soon as the flow control windows are full
+    let upstream = async {
+        let sent = tokio::io::copy(&mut reader, &mut send_stream).await?;
+        send_stream.finish()?;
+        send_stream.stopped().await?;
+        anyhow::Ok(sent)
+    };
+    let downstream = async {
+        let received = tokio::io::copy(&mut recv_stream, &mut writer).await?;
+        writer.shutdown().await?;
+        anyhow::Ok(received)
+    };
+    tokio::try_join!(upstream, downstream)
+}
+
+/// Forwarding protocol handler, connects every stream to the target
+#[derive(Debug, Clone)]
+pub struct ForwardProtocol {
+    target: Arc<Address>,
+}
+
+impl ForwardProtocol {
+    /// Create a new forwarding handler for a target address
+    pub fn new(target: Address) -> Self {
+        Self {
+            target: Arc::new(target),
+        }
+    }
+
+    /// Forward a single stream to the target
+    async fn forward_stream(
+        self,
+        send_stream: SendStream,
+        mut recv_stream: RecvStream,
+    ) -> Result<()> {
+        read_hello(&mut recv_stream).await?;
+        let (sent, received) = match &*self.target {
+            Address::Tcp(addr) => {
+                let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+                pipe(reader, writer, send_stream, recv_stream).await?
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                let (reader, writer) = UnixStream::connect(path).await?.into_split();
+                pipe(reader, writer, send_stream, recv_stream).await?
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => anyhow::bail!("Unix domain sockets are not supported here"),
+        };
+        info!(
+            "Forwarded stream to {} closed, sent {} bytes, received {} bytes",
+            self.target, sent, received
+        );
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for ForwardProtocol {
+    fn accept(
+        &self,
+        conn: Connection,
+    ) -> Pin<Box<dyn std::future::Future<Output = Result<()>> + Send + 'static>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let remote = conn.remote_node_id()?;
+            info!("Forwarding connection accepted from {}", remote);
+
+            // Every forwarded local connection comes in on its own stream
+            let mut tasks = JoinSet::new();
+            let res: Result<()> = loop {
+                let (send_stream, recv_stream) = match conn.accept_bi().await {
+                    Ok(streams) => streams,
+                    Err(ConnectionError::ApplicationClosed(_)) => break Ok(()),
+                    Err(cause) => break Err(cause.into()),
+                };
+                let this = this.clone();
+                tasks.spawn(async move {
+                    if let Err(cause) = this.forward_stream(send_stream, recv_stream).await {
+                        warn!("Failed to forward stream: {:?}", cause);
+                    }
+                });
+            };
+
+            // Let open forwards finish, also if the connection failed
+            while tasks.join_next().await.is_some() {}
+            info!("Forwarding connection closed");
+
+            res
+        })
+    }
+}
+
+/// Tunnel a single local connection over its own stream
+async fn tunnel(
+    conn: &Connection,
+    reader: impl AsyncRead + Unpin + Send + 'static,
+    writer: impl AsyncWrite + Unpin + Send + 'static,
+    peer: String,
+) -> Result<()> {
+    let (send_stream, recv_stream) = open_stream(conn).await?;
+    tokio::spawn(async move {
+        info!("Forwarding local connection from {}", peer);
+        match pipe(reader, writer, send_stream, recv_stream).await {
+            Ok((sent, received)) => info!(
+                "Local connection from {} closed, sent {} bytes, received {} bytes",
+                peer, sent, received
+            ),
+            Err(cause) => warn!(
+                "Failed to forward local connection from {}: {:?}",
+                peer, cause
+            ),
+        }
+    });
+    Ok(())
+}
+
+/// Removes the socket file of a Unix domain socket we bound when dropped
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        // So the next run can bind it again
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Accept local connections on `addr` and tunnel each of them over `conn`
+///
+/// Runs until the connection to the remote node is lost. The socket file of
+/// a Unix domain socket is removed when this returns or is cancelled, but
+/// only if the bind succeeded, so a file owned by someone else is kept.
+pub async fn listen(conn: &Connection, addr: &Address) -> Result<()> {
+    let remote = conn.remote_node_id()?;
+    match addr {
+        Address::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            println!("Forwarding {} to {}", listener.local_addr()?, remote);
+            loop {
+                let (stream, peer) = tokio::select! {
+                    res = listener.accept() => res?,
+                    cause = conn.closed() => return Err(cause.into()),
+                };
+                let (reader, writer) = stream.into_split();
+                tunnel(conn, reader, writer, peer.to_string()).await?;
+            }
+        }
+        #[cfg(unix)]
+        Address::Unix(path) => {
+            let listener = UnixListener::bind(path)?;
+            let _socket_file = SocketFile(path.clone());
+            println!("Forwarding unix:{} to {}", path.display(), remote);
+            loop {
+                let (stream, _) = tokio::select! {
+                    res = listener.accept() => res?,
+                    cause = conn.closed() => return Err(cause.into()),
+                };
+                let (reader, writer) = stream.into_split();
+                tunnel(conn, reader, writer, format!("unix:{}", path.display())).await?;
+            }
+        }
+        #[cfg(not(unix))]
+        Address::Unix(_) => anyhow::bail!("Unix domain sockets are not supported here"),
+    }
+}
@@ -0,0 +1,366 @@
+//! Load generator for the echo service
+//!
+//! Opens many connections from several local endpoints and sends framed
+//! messages on each connection at a target rate, every message on its own
+//! stream. New streams are opened on schedule even if earlier ones have not
+//! finished yet, so a slow server shows up as growing latencies and errors
+//! instead of a lower request rate. Every local endpoint has its own node id,
+//! so per-node limits on the server apply to each of them separately.
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Context, Result};
+use common::{
+    access,
+    dial::{self, DialOptions},
+    framing, limits,
+};
+use iroh::{endpoint::Connection, Endpoint, NodeAddr};
+use tokio::{task::JoinSet, time::MissedTickBehavior};
+
+use crate::ping::{format_rate, millis, RttStats};
+
+/// How long to wait for streams still in flight when the run is over
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bounds of the latency histogram buckets, in milliseconds
+const BUCKETS_MS: [u64; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+
+/// Width of the longest bar in the latency histogram
+const BAR_WIDTH: usize = 40;
+
+/// Lowest stream rate per connection, one stream every 1000 seconds
+const MIN_STREAM_RATE: f64 = 0.001;
+
+/// Highest stream rate per connection, one stream every microsecond
+const MAX_STREAM_RATE: f64 = 1_000_000.0;
+
+/// Shape of the load
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// Number of connections, spread evenly over the endpoints
+    pub connections: usize,
+    /// Streams per second to open on every connection
+    pub stream_rate: f64,
+    /// How long to send streams once all connections are dialed
+    pub duration: Duration,
+    /// Size of each message
+    pub size: usize,
+}
+
+/// Latencies grouped into fixed buckets
+#[derive(Debug, Default)]
+pub struct Histogram {
+    counts: [usize; BUCKETS_MS.len() + 1],
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let ms = millis(latency);
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|bound| ms < *bound as f64)
+            .unwrap_or(BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let max = self.counts.iter().copied().max().unwrap_or(0).max(1);
+        for (i, count) in self.counts.iter().enumerate() {
+            let label = match BUCKETS_MS.get(i) {
+                Some(bound) => format!("< {} ms", bound),
+                None => format!(">= {} ms", BUCKETS_MS[BUCKETS_MS.len() - 1]),
+            };
+            let bar = "#".repeat(count * BAR_WIDTH / max);
+            writeln!(f, "  {:>10} {:>8} {}", label, count, bar)?;
+        }
+        Ok(())
+    }
+}
+
+/// Results of a load run
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    pub endpoints: usize,
+    pub connections: usize,
+    /// Time it took to dial each of the established connections
+    pub dial_times: Vec<Duration>,
+    /// Round trip time of every successful stream
+    pub latencies: Vec<Duration>,
+    pub histogram: Histogram,
+    /// Bytes echoed back by successful streams
+    pub bytes: u64,
+    /// Number of failures, grouped by their cause
+    pub errors: BTreeMap<String, usize>,
+    /// Time spent sending streams, without dialing
+    pub elapsed: Duration,
+}
+
+impl LoadReport {
+    fn record_error(&mut self, cause: &anyhow::Error) {
+        *self.errors.entry(error_kind(cause)).or_default() += 1;
+    }
+
+    fn record_stream(&mut self, res: Result<(Duration, u64)>) {
+        match res {
+            Ok((latency, bytes)) => {
+                self.latencies.push(latency);
+                self.histogram.record(latency);
+                self.bytes += bytes;
+            }
+            Err(cause) => self.record_error(&cause),
+        }
+    }
+
+    fn merge(&mut self, other: LoadReport) {
+        self.latencies.extend(other.latencies);
+        for (count, other) in self.histogram.counts.iter_mut().zip(other.histogram.counts) {
+            *count += other;
+        }
+        self.bytes += other.bytes;
+        for (kind, count) in other.errors {
+            *self.errors.entry(kind).or_default() += count;
+        }
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Connections: {}/{} established from {} endpoints",
+            self.dial_times.len(),
+            self.connections,
+            self.endpoints
+        )?;
+        if let Some(stats) = RttStats::from_samples(&self.dial_times) {
+            writeln!(
+                f,
+                "Dial time min/avg/max/p99 = {:.2}/{:.2}/{:.2}/{:.2} ms",
+                millis(stats.min),
+                millis(stats.avg),
+                millis(stats.max),
+                millis(stats.p99)
+            )?;
+        }
+        let failed: usize = self.errors.values().sum();
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        writeln!(
+            f,
+            "Streams: {} ok, {} failed in {:.2} s ({:.1} streams/s)",
+            self.latencies.len(),
+            failed,
+            secs,
+            self.latencies.len() as f64 / secs
+        )?;
+        writeln!(
+            f,
+            "Throughput: echoed {} bytes, {}",
+            self.bytes,
+            format_rate(self.bytes, self.elapsed)
+        )?;
+        if let Some(stats) = RttStats::from_samples(&self.latencies) {
+            writeln!(f, "{}", stats)?;
+            writeln!(f, "Latency histogram:")?;
+            write!(f, "{}", self.histogram)?;
+        }
+        if !self.errors.is_empty() {
+            writeln!(f, "Errors:")?;
+            for (kind, count) in &self.errors {
+                writeln!(f, "  {:>8} {}", count, kind)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Group an error by its cause, keeping the reason of rejections by the server
+fn error_kind(cause: &anyhow::Error) -> String {
+    if let Some(reason) = access::denied_reason(cause) {
+        format!("access denied: {}", reason)
+    } else if let Some(reason) = limits::rejected_reason(cause) {
+        format!("rejected: {}", reason)
+    } else {
+        cause.root_cause().to_string()
+    }
+}
+
+/// Streams finished so far, shared with the progress printer
+#[derive(Debug, Default)]
+struct Progress {
+    ok: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+/// Run the load against the node at `addr`, using `endpoints` to connect and
+/// offering `alpns`
+pub async fn run(
+    endpoints: &[Endpoint],
+    addr: NodeAddr,
+    alpns: &[&'static [u8]],
+    options: &LoadOptions,
+    dial_options: &DialOptions,
+) -> Result<LoadReport> {
+    ensure!(!endpoints.is_empty(), "at least one endpoint is needed");
+    ensure!(
+        (MIN_STREAM_RATE..=MAX_STREAM_RATE).contains(&options.stream_rate),
+        "the stream rate must be between {} and {} streams/s",
+        MIN_STREAM_RATE,
+        MAX_STREAM_RATE
+    );
+    let mut report = LoadReport {
+        endpoints: endpoints.len(),
+        connections: options.connections,
+        ..Default::default()
+    };
+
+    // Dial all connections concurrently, round robin over the endpoints
+    let mut dials = JoinSet::new();
+    for i in 0..options.connections {
+        let ep = endpoints[i % endpoints.len()].clone();
+        let addr = addr.clone();
+        let alpns = alpns.to_vec();
+        let dial_options = dial_options.clone();
+        dials.spawn(async move {
+            let start = Instant::now();
+            let conn = dial::connect(&ep, addr, &alpns, &dial_options).await?;
+            anyhow::Ok((conn, start.elapsed()))
+        });
+    }
+    let mut conns = Vec::with_capacity(options.connections);
+    while let Some(res) = dials.join_next().await {
+        match res? {
+            Ok((conn, elapsed)) => {
+                report.dial_times.push(elapsed);
+                conns.push(conn);
+            }
+            Err(cause) => report.record_error(&cause.context("dial failed")),
+        }
+    }
+    println!(
+        "Established {}/{} connections, sending {} streams/s on each for {:.0} s",
+        conns.len(),
+        options.connections,
+        options.stream_rate,
+        options.duration.as_secs_f64()
+    );
+
+    // Drive all connections until the deadline, printing progress every second
+    let progress = Arc::new(Progress::default());
+    let start = Instant::now();
+    let deadline = tokio::time::Instant::now()
+        .checked_add(options.duration)
+        .context("the duration is too long")?;
+    let payload: Arc<[u8]> = vec![0xAB; options.size].into();
+    let mut drivers = JoinSet::new();
+    for conn in conns {
+        drivers.spawn(drive(
+            conn,
+            payload.clone(),
+            options.stream_rate,
+            deadline,
+            progress.clone(),
+        ));
+    }
+    let printer = tokio::spawn(print_progress(progress.clone()));
+    while let Some(res) = drivers.join_next().await {
+        report.merge(res?);
+    }
+    printer.abort();
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// Print the number of finished streams once a second
+async fn print_progress(progress: Arc<Progress>) {
+    let start = Instant::now();
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        println!(
+            "{:>4.0} s: {} ok, {} failed",
+            start.elapsed().as_secs_f64(),
+            progress.ok.load(Ordering::Relaxed),
+            progress.failed.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Open streams on one connection at the given rate until the deadline
+///
+/// Streams still in flight at the deadline get [`DRAIN_TIMEOUT`] to finish,
+/// the rest are counted as timed out.
+async fn drive(
+    conn: Connection,
+    payload: Arc<[u8]>,
+    stream_rate: f64,
+    deadline: tokio::time::Instant,
+    progress: Arc<Progress>,
+) -> LoadReport {
+    let mut report = LoadReport::default();
+    let mut streams = JoinSet::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / stream_rate));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let record = |report: &mut LoadReport, res: Result<(Duration, u64)>| {
+        let counter = if res.is_ok() {
+            &progress.ok
+        } else {
+            &progress.failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        report.record_stream(res);
+    };
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => break,
+            _ = ticker.tick() => {
+                streams.spawn(send_one(conn.clone(), payload.clone()));
+            }
+            Some(res) = streams.join_next() => {
+                record(&mut report, res.map_err(anyhow::Error::from).and_then(|res| res));
+            }
+        }
+    }
+
+    let drain = tokio::time::sleep(DRAIN_TIMEOUT);
+    tokio::pin!(drain);
+    loop {
+        tokio::select! {
+            _ = &mut drain => {
+                for _ in 0..streams.len() {
+                    record(&mut report, Err(anyhow::anyhow!("timed out after the run")));
+                }
+                streams.abort_all();
+                break;
+            }
+            res = streams.join_next() => match res {
+                Some(res) => {
+                    record(&mut report, res.map_err(anyhow::Error::from).and_then(|res| res));
+                }
+                None => break,
+            }
+        }
+    }
+
+    conn.close(0u8.into(), b"done");
+    report
+}
+
+/// Send one message on its own stream, returns the round trip time and size
+async fn send_one(conn: Connection, payload: Arc<[u8]>) -> Result<(Duration, u64)> {
+    let start = Instant::now();
+    let res = framing::send_message(&conn, &payload, payload.len()).await?;
+    let rtt = start.elapsed();
+    ensure!(*res == *payload, "echo does not match the message");
+    Ok((rtt, res.len() as u64))
+}
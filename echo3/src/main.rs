@@ -1,55 +1,147 @@
-use std::{env, process, str::FromStr};
+use std::{
+    env,
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+    str::FromStr,
+    time::Duration,
+};
 
 use anyhow::{Context, Result};
-use iroh::{discovery, protocol::Router, Endpoint, NodeAddr};
+use common::{
+    access, cli, diag, dial, echo, framing, keys,
+    limits::{self, Limits},
+    metrics, rpc,
+};
+use iroh::{
+    endpoint::{Connection, RecvStream, SendStream, VarInt},
+    protocol::Router,
+    Endpoint, NodeAddr, NodeId,
+};
 use iroh_base::ticket::NodeTicket;
-use tokio::signal;
-use tracing::info;
+use iroh_gossip::net::Gossip;
+use net::NetworkOptions;
+use tokio::{
+    signal,
+    sync::oneshot,
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{info, warn};
 use util::z32_node_id;
 
-mod echo;
+mod address_book;
+mod chat;
+mod echo_rpc;
+mod forward;
+mod load;
+mod net;
+mod ping;
+mod pubsub;
 mod util;
+mod whoami;
 
-/// Server mode - accepts connections and echoes messages back
-async fn accept() -> Result<()> {
+/// Create the server endpoint and print how to reach it to `out`
+async fn bind_server(net: &NetworkOptions, out: &mut dyn Write) -> Result<(Endpoint, NodeTicket)> {
     // Get or generate a secret key
-    let secret_key = util::get_or_generate_secret_key()?;
+    let secret_key = keys::get_or_generate_secret_key()?;
 
     // Create an endpoint and print the node ID
-    let ep = Endpoint::builder()
-        .secret_key(secret_key)
-        .discovery_n0()
-        .discovery_dht()
-        .bind()
-        .await?;
+    let ep = net.bind_server(secret_key).await?;
 
     let node_id = ep.node_id();
-    let addr = ep.node_addr().await?;
+    let addr = net.ticket_addr(ep.node_addr().await?);
     let ticket = NodeTicket::from(addr.clone());
     let ticket_short = NodeTicket::from(NodeAddr::from(addr.node_id));
 
-    println!("Node ID: {}", node_id);
-    println!("Full address: {:?}", addr);
-    println!("Ticket: {}", ticket);
-    println!("Short ticket: {}", ticket_short);
+    writeln!(out, "Node ID: {}", node_id)?;
+    writeln!(out, "Full address: {:?}", addr)?;
+    writeln!(out, "Ticket: {}", ticket)?;
+    writeln!(out, "Short ticket: {}", ticket_short)?;
+    if net.local {
+        writeln!(
+            out,
+            "Discoverable on the local network, the short ticket works with --local"
+        )?;
+        return Ok((ep, ticket));
+    }
+    writeln!(out, "To see the info published on DNS, run:")?;
+    writeln!(out, "{}", net.dig_command(&addr.node_id))?;
+    writeln!(out, "To see the info published on the mainline DHT, open:")?;
+    writeln!(
+        out,
+        "https://app.pkarr.org/?pk={}",
+        z32_node_id(&addr.node_id)
+    )?;
+
+    Ok((ep, ticket))
+}
+
+/// Server mode - accepts connections and echoes messages back
+///
+/// The echo service is served in all the given versions side by side, and
+/// in the legacy format. If an access list file is given, only nodes allowed
+/// by it can connect.
+/// If a metrics address is given, the endpoint metrics are served on it.
+/// RPC requests that take longer than `rpc_timeout` are answered with an error.
+async fn accept(
+    net: &NetworkOptions,
+    versions: &[echo::Version],
+    max_message_size: usize,
+    limits: Limits,
+    acl: Option<PathBuf>,
+    metrics_addr: Option<SocketAddr>,
+    rpc_timeout: Duration,
+) -> Result<()> {
+    let (ep, ticket) = bind_server(net, &mut io::stdout()).await?;
     println!(
         "To connect, use: {} connect <message> {}",
         env::args().next().unwrap_or_default(),
         ticket
     );
-    println!("To see the info published on DNS, run:");
-    println!(
-        "dig TXT @dns.iroh.link _iroh.{}.{}",
-        z32_node_id(&addr.node_id),
-        "dns.iroh.link"
-    );
-    println!("To see the info published on the mainline DHT, open:");
-    println!("https://app.pkarr.org/?pk={}", z32_node_id(&addr.node_id));
+    let metrics_task = metrics_addr.map(|addr| metrics::serve(addr, &ep, Default::default()));
 
     // Create a router with the endpoint
-    let router = Router::builder(ep)
-        .accept(echo::ECHO_ALPN, echo::EchoProtocol)
-        .spawn();
+    let echo = echo::EchoProtocol::new(max_message_size).with_limits(limits);
+    let echo_rpc =
+        rpc::RpcProtocol::new(echo_rpc::EchoService::default()).with_timeout(rpc_timeout);
+    let whoami =
+        rpc::RpcProtocol::new(whoami::WhoamiService::new(ep.clone())).with_timeout(rpc_timeout);
+    let mut reload_task = None;
+    let router = match acl {
+        Some(path) => {
+            let access = access::AccessControl::from_file(path)?;
+            reload_task = Some(access.spawn_reload_task());
+            let echo = access::AccessLimited::new(echo, access.clone());
+            let mut builder = Router::builder(ep.clone());
+            for alpn in echo::server_alpns(versions) {
+                builder = builder.accept(alpn, echo.clone());
+            }
+            builder
+                .accept(
+                    echo_rpc::ECHO_RPC_ALPN,
+                    access::AccessLimited::new(echo_rpc, access.clone()),
+                )
+                .accept(
+                    whoami::WHOAMI_ALPN,
+                    access::AccessLimited::new(whoami, access),
+                )
+                .spawn()
+        }
+        None => {
+            let mut builder = Router::builder(ep.clone());
+            for alpn in echo::server_alpns(versions) {
+                builder = builder.accept(alpn, echo.clone());
+            }
+            builder
+                .accept(echo_rpc::ECHO_RPC_ALPN, echo_rpc)
+                .accept(whoami::WHOAMI_ALPN, whoami)
+                .spawn()
+        }
+    };
+    let mut alpns = echo::server_alpns(versions);
+    alpns.extend([echo_rpc::ECHO_RPC_ALPN, whoami::WHOAMI_ALPN]);
+    echo::prefer_newest(&ep, &alpns)?;
 
     println!("Server is running. Press Ctrl+C to stop...");
 
@@ -59,41 +151,82 @@ async fn accept() -> Result<()> {
 
     // Gracefully shut down the router
     router.shutdown().await?;
+    if let Some(reload_task) = reload_task {
+        reload_task.abort();
+    }
+    if let Some(metrics_task) = metrics_task {
+        metrics_task.abort();
+    }
 
     Ok(())
 }
 
-/// Client mode - connects to a server and sends a message
-async fn connect(message: &str, ticket: &str) -> Result<()> {
+/// Create a client endpoint and connect to the node in the ticket
+///
+/// With `--diag`, also returns the task that prints the path changes.
+async fn connect_to(
+    net: &NetworkOptions,
+    ticket: &str,
+    alpn: &[u8],
+) -> Result<(Endpoint, Connection, Option<JoinHandle<()>>)> {
+    connect_to_any(net, ticket, &[alpn]).await
+}
+
+/// Connect to an echo server, offering all the given versions
+async fn connect_echo(
+    net: &NetworkOptions,
+    ticket: &str,
+    versions: &[echo::Version],
+) -> Result<(
+    Endpoint,
+    Connection,
+    Option<JoinHandle<()>>,
+    echo::Negotiated,
+)> {
+    let (ep, conn, diag_task) = connect_to_any(net, ticket, &echo::alpns(versions)).await?;
+    let negotiated = echo::Negotiated::new(&conn).await?;
+    info!("Negotiated {}", negotiated);
+    Ok((ep, conn, diag_task, negotiated))
+}
+
+/// Like [`connect_to`], but offers several ALPNs, most preferred first
+async fn connect_to_any(
+    net: &NetworkOptions,
+    ticket: &str,
+    alpns: &[&[u8]],
+) -> Result<(Endpoint, Connection, Option<JoinHandle<()>>)> {
     // Parse the address using NodeTicket
     let ticket = NodeTicket::from_str(ticket).context("invalid address")?;
 
     info!("Connecting to: {:?}", ticket.node_addr());
 
     // Create an endpoint
-    //
-    // only resolve discovery, don't publish
-    let ep = Endpoint::builder()
-        .add_discovery(|_| Some(discovery::pkarr::PkarrResolver::n0_dns()))
-        .add_discovery(|_| discovery::pkarr::dht::DhtDiscovery::builder().build().ok())
-        .bind()
-        .await?;
-
-    // Connect to the node
-    let conn = ep.connect(ticket, echo::ECHO_ALPN).await?;
-    info!("Connected");
+    let ep = net.bind_client().await?;
 
-    // Open a bi-directional stream
-    let (mut send_stream, mut recv_stream) = conn.open_bi().await?;
+    // Connect to the node, retrying on failure
+    let conn = dial::connect(&ep, ticket, alpns, &net.dial).await?;
+    info!("Connected");
+    net.record_connection(&ep, conn.remote_node_id()?);
+    let diag_task = net.diag.then(|| diag::watch_paths(ep.clone()));
 
-    // Send the message
-    info!("Sending message: {}", message);
-    send_stream.write_all(message.as_bytes()).await?;
-    send_stream.finish()?;
+    Ok((ep, conn, diag_task))
+}
 
-    // Wait for the response
-    let res = recv_stream.read_to_end(1024).await?;
-    println!("Received response: {}", String::from_utf8_lossy(&res));
+/// Close the connection and the endpoint, waiting for the close to go through
+///
+/// Stops the path diagnostics first, so they do not mix with the statistics.
+async fn disconnect(
+    net: &NetworkOptions,
+    ep: Endpoint,
+    conn: Connection,
+    diag_task: Option<JoinHandle<()>>,
+) {
+    if let Some(diag_task) = diag_task {
+        diag_task.abort();
+    }
+    if net.diag {
+        diag::print_stats(&conn.stats());
+    }
 
     // Close the connection
     conn.close(0u8.into(), b"done");
@@ -104,6 +237,528 @@ async fn connect(message: &str, ticket: &str) -> Result<()> {
 
     // Close the endpoint
     ep.close().await;
+}
+
+/// Client mode - connects to a server and sends one or more messages
+///
+/// Every message is sent on its own bi-directional stream, and all streams
+/// share a single connection. In streaming mode without messages, stdin is
+/// streamed to the server and the echo is written to stdout.
+async fn connect(
+    net: &NetworkOptions,
+    versions: &[echo::Version],
+    messages: &[String],
+    ticket: &str,
+    max_message_size: usize,
+    streaming: bool,
+) -> Result<()> {
+    let (ep, conn, diag_task, negotiated) = connect_echo(net, ticket, versions).await?;
+    if !streaming {
+        for message in messages {
+            negotiated.check_message_size(message.len())?;
+        }
+    }
+
+    if streaming && messages.is_empty() {
+        // Stream stdin through the echo service to stdout
+        let (sent, received) =
+            framing::stream_through(&conn, tokio::io::stdin(), tokio::io::stdout()).await?;
+        info!("Sent {} bytes, received {} bytes", sent, received);
+    } else {
+        // Send all messages concurrently, each on its own stream
+        let mut tasks = JoinSet::new();
+        for (i, message) in messages.iter().cloned().enumerate() {
+            let conn = conn.clone();
+            tasks.spawn(async move {
+                info!("Sending message: {}", message);
+                let res = if streaming {
+                    let mut res = Vec::new();
+                    framing::stream_through(&conn, message.as_bytes(), &mut res).await?;
+                    res
+                } else {
+                    framing::send_message(&conn, message.as_bytes(), max_message_size).await?
+                };
+                anyhow::Ok((i, res))
+            });
+        }
+
+        // Print the responses in the order the messages were given
+        let mut responses = Vec::with_capacity(messages.len());
+        while let Some(res) = tasks.join_next().await {
+            responses.push(res??);
+        }
+        responses.sort_by_key(|(i, _)| *i);
+        for (_, res) in responses {
+            println!("Received response: {}", String::from_utf8_lossy(&res));
+        }
+    }
+
+    disconnect(net, ep, conn, diag_task).await;
+
+    Ok(())
+}
+
+/// Ping mode - measures round trip times over a single connection
+///
+/// With `datagrams`, uses unreliable datagrams instead of streams and also
+/// reports loss and reordering.
+async fn ping(
+    net: &NetworkOptions,
+    versions: &[echo::Version],
+    ticket: &str,
+    count: usize,
+    size: usize,
+    interval: Duration,
+    datagrams: bool,
+) -> Result<()> {
+    let (ep, conn, diag_task, negotiated) = connect_echo(net, ticket, versions).await?;
+    let remote = conn.remote_node_id()?;
+    if !datagrams {
+        negotiated.check_message_size(size)?;
+    }
+
+    if datagrams {
+        let stats = ping::datagram_ping(&conn, count, size, interval).await?;
+        println!("{}", stats);
+    } else {
+        let rtts = ping::ping(&conn, count, size, interval).await?;
+        if let Some(stats) = ping::RttStats::from_samples(&rtts) {
+            println!("{}", stats);
+        }
+    }
+    println!("Path: {}", diag::describe_path(&ep, remote));
+    println!("Protocol: {}", negotiated);
+
+    disconnect(net, ep, conn, diag_task).await;
+
+    Ok(())
+}
+
+/// Throughput mode - streams a number of bytes through the echo service
+async fn throughput(
+    net: &NetworkOptions,
+    versions: &[echo::Version],
+    ticket: &str,
+    total: u64,
+) -> Result<()> {
+    let (ep, conn, diag_task, negotiated) = connect_echo(net, ticket, versions).await?;
+    let remote = conn.remote_node_id()?;
+
+    let (bytes, elapsed) = ping::throughput(&conn, total).await?;
+    println!(
+        "Echoed {} bytes in {:.2} s: {}",
+        bytes,
+        elapsed.as_secs_f64(),
+        ping::format_rate(bytes, elapsed)
+    );
+    println!("Path: {}", diag::describe_path(&ep, remote));
+    println!("Protocol: {}", negotiated);
+
+    disconnect(net, ep, conn, diag_task).await;
+
+    Ok(())
+}
+
+/// Load mode - runs a load test against an echo server
+///
+/// Binds `endpoints` client endpoints, each with its own node id, and
+/// spreads the connections over them.
+async fn load(
+    net: &NetworkOptions,
+    versions: &[echo::Version],
+    ticket: &str,
+    endpoints: usize,
+    options: load::LoadOptions,
+) -> Result<()> {
+    let ticket = NodeTicket::from_str(ticket).context("invalid address")?;
+    anyhow::ensure!(endpoints > 0, "at least one endpoint is needed");
+
+    let mut eps = Vec::with_capacity(endpoints);
+    for _ in 0..endpoints {
+        eps.push(net.bind_client().await?);
+    }
+    let addr = ticket.node_addr().clone();
+    let report = load::run(&eps, addr, &echo::alpns(versions), &options, &net.dial).await?;
+    println!("{}", report);
+
+    for ep in eps {
+        ep.close().await;
+    }
+
+    Ok(())
+}
+
+/// Echo a framed and a streamed message and check the echoes
+///
+/// On the legacy ALPN a single message in the legacy format is echoed
+/// instead. Returns a description of the protocol that was used.
+async fn check_echo(conn: &Connection) -> Result<String> {
+    let message = b"compat";
+    if conn.alpn().as_deref() == Some(echo::LEGACY_ECHO_ALPN) {
+        let res = framing::send_legacy_message(conn, message, message.len()).await?;
+        anyhow::ensure!(res == message, "legacy echo does not match the message");
+        return Ok("legacy format".to_string());
+    }
+    let negotiated = echo::Negotiated::new(conn).await?;
+    let res = framing::send_message(conn, message, message.len()).await?;
+    anyhow::ensure!(res == message, "framed echo does not match the message");
+    let mut res = Vec::new();
+    framing::stream_through(conn, &message[..], &mut res).await?;
+    anyhow::ensure!(res == message, "streamed echo does not match the message");
+    Ok(negotiated.to_string())
+}
+
+/// Compatibility mode - checks which echo versions a server speaks
+///
+/// Connects once offering every single version, once with the legacy ALPN
+/// like a client from before framing, and once offering all versions like a
+/// current client, and echoes messages on each connection. Single versions
+/// may be missing, but fails if a legacy or a current client can not use the
+/// server.
+async fn compat(net: &NetworkOptions, ticket: &str) -> Result<()> {
+    let ticket = NodeTicket::from_str(ticket).context("invalid address")?;
+    let ep = net.bind_client().await?;
+    // A missing version fails the handshake, retrying would not help
+    let dial_options = dial::DialOptions {
+        max_attempts: 1,
+        ..net.dial.clone()
+    };
+
+    let mut offers: Vec<(Vec<&[u8]>, bool)> = echo::Version::ALL
+        .iter()
+        .rev()
+        .map(|version| (vec![version.alpn()], false))
+        .collect();
+    offers.push((vec![echo::LEGACY_ECHO_ALPN], true));
+    offers.push((echo::alpns(&echo::Version::ALL), true));
+
+    let mut broken = 0;
+    for (alpns, required) in offers {
+        let offer = alpns
+            .iter()
+            .map(|alpn| String::from_utf8_lossy(alpn))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let res = async {
+            let addr = ticket.node_addr().clone();
+            let conn = dial::connect(&ep, addr, &alpns, &dial_options).await?;
+            let res = check_echo(&conn).await;
+            conn.close(0u8.into(), b"done");
+            res
+        }
+        .await;
+        match res {
+            Ok(protocol) => println!("offer {:<24} -> {}: ok", offer, protocol),
+            Err(cause) => {
+                if required {
+                    broken += 1;
+                }
+                println!("offer {:<24} -> failed: {:#}", offer, cause);
+            }
+        }
+    }
+
+    ep.close().await;
+    anyhow::ensure!(broken == 0, "{} required offers failed", broken);
+
+    Ok(())
+}
+
+/// RPC mode - sends a single typed request to the echo RPC service
+async fn rpc_call(
+    net: &NetworkOptions,
+    request: echo_rpc::EchoRequest,
+    ticket: &str,
+    timeout: Duration,
+) -> Result<()> {
+    let (ep, conn, diag_task) = connect_to(net, ticket, echo_rpc::ECHO_RPC_ALPN).await?;
+    let client = echo_rpc::EchoClient::new(conn.clone()).with_timeout(timeout);
+
+    let res = client.call(request).await;
+    match &res {
+        Ok(echo_rpc::EchoResponse::Echo(message)) => println!("Echo: {}", message),
+        Ok(echo_rpc::EchoResponse::Reverse(message)) => println!("Reversed: {}", message),
+        Ok(echo_rpc::EchoResponse::Count(count)) => {
+            println!("The server has handled {} requests", count)
+        }
+        Err(cause) => match cause.downcast_ref::<rpc::RemoteError>() {
+            Some(err) => println!("The server rejected the request: {}", err),
+            None => println!("Request failed: {:#}", cause),
+        },
+    }
+
+    disconnect(net, ep, conn, diag_task).await;
+
+    res.map(|_| ())
+}
+
+/// Whoami mode - asks the server how it sees us
+async fn whoami(net: &NetworkOptions, ticket: &str) -> Result<()> {
+    let (ep, conn, diag_task) = connect_to(net, ticket, whoami::WHOAMI_ALPN).await?;
+    let remote = conn.remote_node_id()?;
+    let client = whoami::WhoamiClient::new(conn.clone());
+
+    let observed = client.call(whoami::WhoamiRequest).await?;
+    println!("Node ID: {}", observed.node_id);
+    match observed.addr {
+        Some(addr) => println!("Observed address: {}", addr),
+        None => println!("Observed address: unknown, not on a direct path"),
+    }
+    if let Some(relay_url) = &observed.relay_url {
+        println!("Relay: {}", relay_url);
+    }
+    println!("Path seen by the server: {:?}", observed.path);
+    println!("Path seen by us: {}", diag::describe_path(&ep, remote));
+
+    disconnect(net, ep, conn, diag_task).await;
+
+    Ok(())
+}
+
+/// Forward mode, serving side - connects forwarded streams to a local target
+///
+/// If an access list file is given, only nodes allowed by it can forward.
+async fn forward_serve(
+    net: &NetworkOptions,
+    target: forward::Address,
+    acl: Option<PathBuf>,
+) -> Result<()> {
+    let (ep, ticket) = bind_server(net, &mut io::stdout()).await?;
+    println!(
+        "To forward a local port, use: {} forward listen <ip:port> {}",
+        env::args().next().unwrap_or_default(),
+        ticket
+    );
+
+    // Create a router with the endpoint
+    let forward = forward::ForwardProtocol::new(target.clone());
+    let mut reload_task = None;
+    let router = match acl {
+        Some(path) => {
+            let access = access::AccessControl::from_file(path)?;
+            reload_task = Some(access.spawn_reload_task());
+            Router::builder(ep)
+                .accept(
+                    forward::FORWARD_ALPN,
+                    access::AccessLimited::new(forward, access),
+                )
+                .spawn()
+        }
+        None => Router::builder(ep)
+            .accept(forward::FORWARD_ALPN, forward)
+            .spawn(),
+    };
+
+    println!("Forwarding to {}. Press Ctrl+C to stop...", target);
+
+    // Wait for Ctrl-C
+    signal::ctrl_c().await?;
+    println!("\nReceived Ctrl+C, shutting down...");
+
+    // Gracefully shut down the router
+    router.shutdown().await?;
+    if let Some(reload_task) = reload_task {
+        reload_task.abort();
+    }
+
+    Ok(())
+}
+
+/// Forward mode, listening side - tunnels local TCP connections to the remote node
+async fn forward_listen(net: &NetworkOptions, addr: forward::Address, ticket: &str) -> Result<()> {
+    let (ep, conn, diag_task) = connect_to(net, ticket, forward::FORWARD_ALPN).await?;
+
+    tokio::select! {
+        res = forward::listen(&conn, &addr) => res?,
+        _ = signal::ctrl_c() => {
+            println!("\nReceived Ctrl+C, shutting down...");
+        }
+    }
+
+    disconnect(net, ep, conn, diag_task).await;
+
+    Ok(())
+}
+
+/// Pipe stdin and stdout through a stream
+///
+/// With `recv_only`, stdin is not read and our side of the stream is finished
+/// right away, so the peer is done once it has sent everything even if our
+/// stdin is a terminal that never ends.
+async fn pipe_stdio(
+    send_stream: SendStream,
+    recv_stream: RecvStream,
+    recv_only: bool,
+) -> Result<()> {
+    let (sent, received) = if recv_only {
+        forward::pipe(
+            tokio::io::empty(),
+            tokio::io::stdout(),
+            send_stream,
+            recv_stream,
+        )
+        .await?
+    } else {
+        forward::pipe(
+            tokio::io::stdin(),
+            tokio::io::stdout(),
+            send_stream,
+            recv_stream,
+        )
+        .await?
+    };
+    info!("Sent {} bytes, received {} bytes", sent, received);
+    Ok(())
+}
+
+/// Pipe mode, accepting side - connects stdin and stdout to the first peer
+///
+/// All other output goes to stderr, so stdout only carries the piped data.
+/// If an access list file is given, only nodes allowed by it can connect.
+async fn pipe_accept(net: &NetworkOptions, acl: Option<PathBuf>, recv_only: bool) -> Result<()> {
+    let (ep, ticket) = bind_server(net, &mut io::stderr()).await?;
+    ep.set_alpns(vec![forward::PIPE_ALPN.to_vec()])?;
+    eprintln!(
+        "To connect, use: {} connect --pipe {}",
+        env::args().next().unwrap_or_default(),
+        ticket
+    );
+    let access = acl.map(access::AccessControl::from_file).transpose()?;
+
+    // Accept the first allowed connection
+    eprintln!("Waiting for a peer to connect...");
+    let conn = loop {
+        let incoming = ep.accept().await.context("no incoming connection")?;
+        let conn = match incoming.await {
+            Ok(conn) => conn,
+            Err(cause) => {
+                warn!("Failed to accept a connection: {:?}", cause);
+                continue;
+            }
+        };
+        let remote = conn.remote_node_id()?;
+        if let Some(Err(reason)) = access.as_ref().map(|access| access.check(&remote)) {
+            info!("Rejecting connection from {}: {}", remote, reason);
+            conn.close(VarInt::from_u32(access::ACCESS_DENIED), reason.as_bytes());
+            continue;
+        }
+        break conn;
+    };
+    eprintln!("Connected to {}", conn.remote_node_id()?);
+
+    let (send_stream, mut recv_stream) = conn.accept_bi().await?;
+    forward::read_hello(&mut recv_stream).await?;
+    pipe_stdio(send_stream, recv_stream, recv_only).await?;
+
+    // Wait for the peer to close the connection
+    conn.closed().await;
+    ep.close().await;
+
+    Ok(())
+}
+
+/// Pipe mode, connecting side - connects stdin and stdout to the peer
+///
+/// Diagnostics are printed to stdout, which carries the piped data, so
+/// `--diag` is refused.
+async fn pipe_connect(net: &NetworkOptions, ticket: &str, recv_only: bool) -> Result<()> {
+    anyhow::ensure!(!net.diag, "--diag can not be combined with --pipe");
+    let (ep, conn, diag_task) = connect_to(net, ticket, forward::PIPE_ALPN).await?;
+
+    let (send_stream, recv_stream) = forward::open_stream(&conn).await?;
+    pipe_stdio(send_stream, recv_stream, recv_only).await?;
+
+    disconnect(net, ep, conn, diag_task).await;
+
+    Ok(())
+}
+
+/// Chat mode, listening side - waits for a peer to join and chats with it
+async fn chat_accept(net: &NetworkOptions) -> Result<()> {
+    let (ep, ticket) = bind_server(net, &mut io::stdout()).await?;
+    println!(
+        "To join, use: {} chat {}",
+        env::args().next().unwrap_or_default(),
+        ticket
+    );
+
+    // Create a router that hands the first chat connection to us
+    let (sender, receiver) = oneshot::channel();
+    let router = Router::builder(ep)
+        .accept(chat::CHAT_ALPN, chat::ChatProtocol::new(sender))
+        .spawn();
+
+    println!("Waiting for a peer to join. Press Ctrl+C to stop...");
+    tokio::select! {
+        conn = receiver => {
+            let conn = conn.context("chat handler stopped")?;
+            chat::run(conn).await?;
+        }
+        _ = signal::ctrl_c() => {
+            println!("\nReceived Ctrl+C, shutting down...");
+        }
+    }
+
+    // Gracefully shut down the router
+    router.shutdown().await?;
+
+    Ok(())
+}
+
+/// Chat mode, joining side - connects to a listening peer and chats with it
+async fn chat_connect(net: &NetworkOptions, ticket: &str) -> Result<()> {
+    let (ep, conn, diag_task) = connect_to(net, ticket, chat::CHAT_ALPN).await?;
+    let res = chat::run(conn).await;
+    if let Some(diag_task) = diag_task {
+        diag_task.abort();
+    }
+    res?;
+
+    // Close the endpoint, this waits for the close to reach the peer
+    ep.close().await;
+
+    Ok(())
+}
+
+/// Pubsub mode - joins a gossip topic and chats with everyone in it
+///
+/// `topic` is either a pubsub ticket or a name to derive the topic from.
+/// `peers` are node tickets or node ids of nodes to bootstrap from, in
+/// addition to the ones in the ticket.
+async fn pubsub(net: &NetworkOptions, topic: &str, peers: &[String]) -> Result<()> {
+    let (topic, mut bootstrap) = match pubsub::PubsubTicket::from_str(topic) {
+        Ok(ticket) => (ticket.topic, ticket.nodes),
+        Err(_) => (pubsub::topic_from_name(topic), Vec::new()),
+    };
+    for peer in peers {
+        let addr = match NodeTicket::from_str(peer) {
+            Ok(ticket) => ticket.node_addr().clone(),
+            Err(_) => NodeAddr::from(NodeId::from_str(peer).context("invalid peer")?),
+        };
+        bootstrap.push(addr);
+    }
+
+    let (ep, _) = bind_server(net, &mut io::stdout()).await?;
+    let gossip = Gossip::builder().spawn(ep.clone()).await?;
+    let router = Router::builder(ep.clone())
+        .accept(iroh_gossip::ALPN, gossip.clone())
+        .spawn();
+
+    // Others can bootstrap from us and from everyone we bootstrap from
+    let mut nodes = vec![net.ticket_addr(ep.node_addr().await?)];
+    nodes.extend(bootstrap.iter().cloned());
+    let ticket = pubsub::PubsubTicket { topic, nodes };
+    println!("Topic: {}", topic);
+    println!(
+        "To join, use: {} pubsub {}",
+        env::args().next().unwrap_or_default(),
+        ticket
+    );
+
+    pubsub::run(&ep, &gossip, topic, bootstrap).await?;
+
+    // Gracefully shut down the router, this also closes the endpoint
+    router.shutdown().await?;
 
     Ok(())
 }
@@ -111,27 +766,362 @@ async fn connect(message: &str, ticket: &str) -> Result<()> {
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize tracing with default configuration
-    tracing_subscriber::fmt::init();
+    //
+    // Logs go to stderr, so stdout only carries the data in pipe mode
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(io::stderr)
+        .init();
 
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let max_message_size = cli::take_option(&mut args, "--max-size")?
+        .map(|size| size.parse::<usize>())
+        .transpose()
+        .context("invalid --max-size")?
+        .unwrap_or(framing::DEFAULT_MAX_MESSAGE_SIZE);
+    let streaming = cli::take_flag(&mut args, "--stream");
+    let pipe = cli::take_flag(&mut args, "--pipe");
+    let recv_only = cli::take_flag(&mut args, "--recv-only");
+    let acl = cli::take_option(&mut args, "--acl")?.map(PathBuf::from);
+    let metrics_addr = cli::take_option(&mut args, "--metrics-addr")?
+        .map(|addr| addr.parse::<SocketAddr>())
+        .transpose()
+        .context("invalid --metrics-addr")?;
+    let limits = Limits {
+        max_connections: cli::take_option(&mut args, "--max-connections")?
+            .map(|max| max.parse())
+            .transpose()
+            .context("invalid --max-connections")?,
+        max_connections_per_node: cli::take_option(&mut args, "--max-connections-per-node")?
+            .map(|max| max.parse())
+            .transpose()
+            .context("invalid --max-connections-per-node")?,
+        max_streams_per_connection: cli::take_option(&mut args, "--max-streams")?
+            .map(|max| max.parse())
+            .transpose()
+            .context("invalid --max-streams")?,
+        bytes_per_second: cli::take_option(&mut args, "--rate")?
+            .map(|rate| rate.parse())
+            .transpose()
+            .context("invalid --rate")?,
+    };
+    let datagrams = cli::take_flag(&mut args, "--datagram");
+    let count = cli::take_option(&mut args, "--count")?
+        .map(|count| count.parse::<usize>())
+        .transpose()
+        .context("invalid --count")?
+        .unwrap_or(10);
+    let size = cli::take_option(&mut args, "--size")?
+        .map(|size| size.parse::<usize>())
+        .transpose()
+        .context("invalid --size")?
+        .unwrap_or(32);
+    let interval = cli::take_option(&mut args, "--interval")?
+        .map(|ms| ms.parse::<u64>())
+        .transpose()
+        .context("invalid --interval")?
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::from_secs(1));
+    let total = cli::take_option(&mut args, "--bytes")?
+        .map(|bytes| bytes.parse::<u64>())
+        .transpose()
+        .context("invalid --bytes")?
+        .unwrap_or(100 * 1024 * 1024);
+    let endpoints = cli::take_option(&mut args, "--endpoints")?
+        .map(|n| n.parse::<usize>())
+        .transpose()
+        .context("invalid --endpoints")?
+        .unwrap_or(1);
+    let load_options = load::LoadOptions {
+        connections: cli::take_option(&mut args, "--connections")?
+            .map(|n| n.parse())
+            .transpose()
+            .context("invalid --connections")?
+            .unwrap_or(10),
+        stream_rate: cli::take_option(&mut args, "--stream-rate")?
+            .map(|rate| rate.parse())
+            .transpose()
+            .context("invalid --stream-rate")?
+            .unwrap_or(10.0),
+        duration: cli::take_option(&mut args, "--duration")?
+            .map(|secs| secs.parse::<u64>())
+            .transpose()
+            .context("invalid --duration")?
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(10)),
+        size,
+    };
+    let timeout = cli::take_option(&mut args, "--timeout")?
+        .map(|ms| ms.parse::<u64>())
+        .transpose()
+        .context("invalid --timeout")?
+        .map(Duration::from_millis)
+        .unwrap_or(rpc::DEFAULT_TIMEOUT);
+    let record = cli::take_flag(&mut args, "--record");
+    let address_book = cli::take_option(&mut args, "--address-book")?
+        .map(|path| address_book::AddressBook::load(path, record))
+        .transpose()?;
+    if let Some(address_book) = &address_book {
+        address_book.spawn_reload_task();
+    }
+    let mut versions = Vec::new();
+    while let Some(version) = cli::take_option(&mut args, "--echo-version")? {
+        versions.push(version.parse::<echo::Version>()?);
+    }
+    if versions.is_empty() {
+        versions = echo::Version::ALL.to_vec();
+    }
+    // Newest first, so clients prefer the newest version
+    versions.sort_by(|a, b| b.cmp(a));
+    versions.dedup();
+    let mut relays = Vec::new();
+    while let Some(url) = cli::take_option(&mut args, "--relay")? {
+        relays.push(url.parse().context("invalid --relay")?);
+    }
+    let dial = dial::DialOptions::from_args(&mut args)?;
+    let net = NetworkOptions {
+        local: cli::take_flag(&mut args, "--local"),
+        pkarr_relay: cli::take_option(&mut args, "--pkarr-relay")?
+            .map(|url| url.parse())
+            .transpose()
+            .context("invalid --pkarr-relay")?,
+        dns_origin: cli::take_option(&mut args, "--dns-origin")?,
+        dns_server: cli::take_option(&mut args, "--dns-server")?
+            .map(|addr| addr.parse())
+            .transpose()
+            .context("invalid --dns-server")?,
+        relays,
+        no_relay: cli::take_flag(&mut args, "--no-relay"),
+        relay_only: cli::take_flag(&mut args, "--relay-only"),
+        address_book,
+        diag: cli::take_flag(&mut args, "--diag"),
+        dial,
+    };
     let cmd = args.get(1).map(|x| x.to_lowercase()).unwrap_or_default();
-    match cmd.as_str() {
+    let res = match cmd.as_str() {
+        "accept" if pipe && args.len() == 2 => {
+            // Pipe mode - connect stdin and stdout to the first peer
+            pipe_accept(&net, acl, recv_only).await
+        }
+        "connect" if pipe && args.len() == 3 => {
+            // Pipe mode - connect stdin and stdout to the peer
+            pipe_connect(&net, &args[2], recv_only).await
+        }
         "accept" if args.len() == 2 => {
             // server mode - accept connections
-            accept().await
+            accept(
+                &net,
+                &versions,
+                max_message_size,
+                limits,
+                acl,
+                metrics_addr,
+                timeout,
+            )
+            .await
+        }
+        "connect" if args.len() >= 4 || (streaming && args.len() == 3) => {
+            // Client mode - connect to a server and send messages
+            let (ticket, messages) = args[2..].split_last().unwrap();
+            connect(
+                &net,
+                &versions,
+                messages,
+                ticket,
+                max_message_size,
+                streaming,
+            )
+            .await
+        }
+        "ping" if args.len() == 3 => {
+            // Client mode - measure round trip times
+            ping(&net, &versions, &args[2], count, size, interval, datagrams).await
         }
-        "connect" if args.len() == 4 => {
-            // Client mode - connect to a server and send a message
-            let message = &args[2];
-            let ticket = &args[3];
-            connect(message, ticket).await
+        "throughput" if args.len() == 3 => {
+            // Client mode - measure throughput
+            throughput(&net, &versions, &args[2], total).await
+        }
+        "load" if args.len() == 3 => {
+            // Client mode - load test an echo server
+            load(&net, &versions, &args[2], endpoints, load_options).await
+        }
+        "compat" if args.len() == 3 => {
+            // Client mode - check which echo versions a server speaks
+            compat(&net, &args[2]).await
+        }
+        "rpc" if args.len() == 5 && matches!(args[2].as_str(), "echo" | "reverse") => {
+            // RPC mode - echo a message, optionally reversed
+            let message = args[3].clone();
+            let request = match args[2].as_str() {
+                "echo" => echo_rpc::EchoRequest::Echo(message),
+                _ => echo_rpc::EchoRequest::Reverse(message),
+            };
+            rpc_call(&net, request, &args[4], timeout).await
+        }
+        "rpc" if args.len() == 4 && args[2] == "count" => {
+            // RPC mode - ask how many requests the server has handled
+            rpc_call(&net, echo_rpc::EchoRequest::Count, &args[3], timeout).await
+        }
+        "whoami" if args.len() == 3 => {
+            // Client mode - ask the server how it sees us
+            whoami(&net, &args[2]).await
+        }
+        "forward" if args.len() == 4 && args[2] == "serve" => {
+            // Forward mode - connect forwarded streams to a local target
+            forward_serve(&net, forward::Address::parse(&args[3]), acl).await
+        }
+        "forward" if args.len() == 5 && args[2] == "listen" => {
+            // Forward mode - tunnel a local port to a remote node
+            forward_listen(&net, forward::Address::parse(&args[3]), &args[4]).await
+        }
+        "chat" if args.len() == 2 => {
+            // Chat mode - wait for a peer to join
+            chat_accept(&net).await
+        }
+        "chat" if args.len() == 3 => {
+            // Chat mode - join a waiting peer
+            chat_connect(&net, &args[2]).await
+        }
+        "pubsub" if args.len() >= 3 => {
+            // Pubsub mode - chat with everyone in a gossip topic
+            pubsub(&net, &args[2], &args[3..]).await
+        }
+        "key" => {
+            // Manage named identity profiles
+            keys::key_command(&args[2..])
         }
         _ => {
             println!("Usage: echo1 <command> [args]");
             println!("Commands:");
-            println!("  accept                       Listen for echo requests");
-            println!("  connect <message> <ticket>   Connect to an echo server and send a message");
+            println!("  accept                         Listen for echo requests");
+            println!(
+                "  connect <message>... <ticket>  Connect to an echo server and send messages"
+            );
+            println!("  connect --stream [<message>...] <ticket>");
+            println!(
+                "                                 Stream messages, or stdin if none are given"
+            );
+            println!("  accept --pipe                  Connect stdin and stdout to the first peer");
+            println!("  connect --pipe <ticket>        Connect stdin and stdout to a piping peer");
+            println!("  ping <ticket>                  Measure round trip times to an echo server");
+            println!(
+                "  ping --datagram <ticket>       Measure round trip times, loss and reordering"
+            );
+            println!("                                 of unreliable datagrams");
+            println!("  throughput <ticket>            Measure throughput to an echo server");
+            println!(
+                "  load <ticket>                  Load test an echo server with many connections"
+            );
+            println!("  compat <ticket>                Check which echo versions a server speaks");
+            println!("  rpc echo <message> <ticket>    Echo a message using the RPC service");
+            println!(
+                "  rpc reverse <message> <ticket> Echo a message reversed using the RPC service"
+            );
+            println!(
+                "  rpc count <ticket>             Ask how many RPC requests the server handled"
+            );
+            println!(
+                "  whoami <ticket>                Ask a server for our observed address and path"
+            );
+            println!("  forward serve <target>         Expose a local service to forwarding peers");
+            println!("  forward listen <addr> <ticket> Tunnel a local port to a forwarding server");
+            println!(
+                "                                 Addresses are host:port, or unix:<path> for"
+            );
+            println!("                                 Unix domain sockets");
+            println!("  chat                           Wait for a peer to join a chat");
+            println!("  chat <ticket>                  Join a chat with a waiting peer");
+            println!(
+                "  pubsub <name> [<peer>...]      Chat with everyone in the topic derived from"
+            );
+            println!(
+                "                                 a name, bootstrapping from node tickets or ids"
+            );
+            println!(
+                "  pubsub <ticket> [<peer>...]    Chat with everyone in the topic of a ticket"
+            );
+            println!("  key <command>                  Manage identity profiles");
+            println!("Options:");
+            println!(
+                "  --local                        Find nodes on the local network only (mDNS)"
+            );
+            println!(
+                "  --pkarr-relay <url>            Publish to and resolve from this pkarr relay"
+            );
+            println!("  --dns-origin <domain>          Resolve node ids below this DNS origin");
+            println!("  --dns-server <ip:port>         Send DNS queries to this server");
+            println!("  --relay <url>                  Use this relay server, can be repeated");
+            println!(
+                "  --no-relay                     Disable relay servers, only connect directly"
+            );
+            println!(
+                "  --relay-only                   Only connect through relays (feature relay-only)"
+            );
+            println!("  --address-book <file>          Also find nodes in an address book file");
+            println!("  --record                       Save the addresses of reached nodes to it");
+            println!(
+                "  --dial-timeout <ms>            Time a single dial may take (default 10000)"
+            );
+            println!("  --max-attempts <n>             Dial attempts before giving up (default 5)");
+            println!(
+                "  --backoff <ms>                 Wait after the first failed dial, doubled every"
+            );
+            println!("                                 attempt (default 500)");
+            println!("  --diag                         Print path changes and connection stats");
+            println!(
+                "  --echo-version <n>             Only serve or offer this echo version, can be"
+            );
+            println!("                                 repeated (default all: 2 and 1)");
+            println!("  --max-size <bytes>             Maximum size of a framed message");
+            println!(
+                "  --acl <file>                   Only accept nodes allowed by an access list"
+            );
+            println!("  --metrics-addr <ip:port>       Serve Prometheus metrics on this address");
+            println!(
+                "  --recv-only                    In pipe mode, only receive and ignore stdin"
+            );
+            println!("  --max-connections <n>          Maximum concurrent echo connections");
+            println!(
+                "  --max-connections-per-node <n> Maximum concurrent echo connections per node"
+            );
+            println!(
+                "  --max-streams <n>              Maximum concurrent streams per echo connection"
+            );
+            println!("  --rate <bytes>                 Maximum echoed bytes per second per node");
+            println!("  --count <n>                    Number of pings (default 10)");
+            println!(
+                "  --size <bytes>                 Size of each ping or load message (default 32)"
+            );
+            println!("  --interval <ms>                Time between pings (default 1000)");
+            println!(
+                "  --bytes <n>                    Bytes to send for throughput (default 100 MiB)"
+            );
+            println!("  --endpoints <n>                Local endpoints for load (default 1)");
+            println!(
+                "  --connections <n>              Connections for load, spread over the endpoints"
+            );
+            println!("                                 (default 10)");
+            println!(
+                "  --stream-rate <n>              Streams per second on every load connection"
+            );
+            println!("                                 (default 10)");
+            println!("  --duration <s>                 Length of the load test (default 10)");
+            println!(
+                "  --timeout <ms>                 Time to wait for an RPC response, or with accept"
+            );
+            println!(
+                "                                 the time to handle a request (default 30000)"
+            );
             process::exit(1);
         }
+    };
+    if let Err(cause) = &res {
+        if let Some(reason) =
+            access::denied_reason(cause).or_else(|| limits::rejected_reason(cause))
+        {
+            eprintln!("Connection rejected by the remote node: {}", reason);
+            process::exit(2);
+        }
     }
+    res
 }
@@ -0,0 +1,209 @@
+//! How endpoints find each other
+//!
+//! By default the server publishes its address to the n0 pkarr relay and the
+//! mainline DHT, and the client resolves it from there. The pkarr relay, the
+//! DNS origin and the DNS server can be replaced with our own infrastructure,
+//! so node address records stay off third-party services. All of these need
+//! network access beyond the LAN. With local discovery, nodes announce
+//! themselves on the local network with mDNS instead, so a ticket that
+//! contains only the node id is enough on an isolated LAN or between two
+//! processes on the same host.
+//!
+//! An address book file can be added to either mode, see [`AddressBook`].
+//!
+//! Connections go through iroh's default relay servers unless a custom
+//! relay map is given or relays are disabled. Relay-only mode never upgrades
+//! to a direct path, to reproduce relay issues deliberately. It relies on the
+//! iroh test utilities, so it is only available with the `relay-only`
+//! feature.
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use common::dial::DialOptions;
+use iroh::{
+    discovery::{
+        dns::DnsDiscovery,
+        pkarr::{dht::DhtDiscovery, PkarrPublisher, PkarrResolver},
+    },
+    dns::DnsResolver,
+    endpoint::Builder,
+    Endpoint, NodeAddr, NodeId, RelayMap, RelayMode, RelayNode, RelayUrl, SecretKey,
+};
+use tracing::warn;
+use url::Url;
+
+use crate::{address_book::AddressBook, util::z32_node_id};
+
+/// The DNS origin of the n0 DNS server, which is also its own nameserver
+const N0_DNS_ORIGIN: &str = "dns.iroh.link";
+
+/// Network options shared by all commands
+#[derive(Debug, Clone, Default)]
+pub struct NetworkOptions {
+    /// Use local network discovery instead of the internet based services
+    pub local: bool,
+    /// Publish to and resolve from this pkarr relay instead of the n0 one
+    pub pkarr_relay: Option<Url>,
+    /// Resolve node ids below this DNS origin instead of the n0 one
+    pub dns_origin: Option<String>,
+    /// Send DNS queries to this server instead of the system resolver
+    pub dns_server: Option<SocketAddr>,
+    /// Use these relay servers instead of the default ones
+    pub relays: Vec<RelayUrl>,
+    /// Do not use any relay servers, only direct connections
+    pub no_relay: bool,
+    /// Only use relay servers, never direct connections
+    pub relay_only: bool,
+    /// Also resolve node ids from an address book file
+    pub address_book: Option<AddressBook>,
+    /// Print connection diagnostics, see [`common::diag`]
+    pub diag: bool,
+    /// Timeout and retries when dialing a node
+    pub dial: DialOptions,
+}
+
+impl NetworkOptions {
+    /// Bind an endpoint that publishes its address, for the server side
+    pub async fn bind_server(&self, secret_key: SecretKey) -> Result<Endpoint> {
+        let builder = self.with_relays(Endpoint::builder().secret_key(secret_key))?;
+        let builder = self.with_dns_server(builder);
+        let builder = if self.local {
+            builder.discovery_local_network()
+        } else {
+            let pkarr_relay = self.pkarr_relay.clone();
+            let dns_origin = self.dns_origin.clone();
+            builder
+                .add_discovery(move |secret_key| {
+                    Some(match pkarr_relay {
+                        Some(url) => PkarrPublisher::new(secret_key.clone(), url),
+                        None => PkarrPublisher::n0_dns(secret_key.clone()),
+                    })
+                })
+                .add_discovery(move |_| {
+                    Some(match dns_origin {
+                        Some(origin) => DnsDiscovery::new(origin),
+                        None => DnsDiscovery::n0_dns(),
+                    })
+                })
+                .discovery_dht()
+        };
+        Ok(self.with_address_book(builder).bind().await?)
+    }
+
+    /// Bind an endpoint that resolves addresses, for the client side
+    pub async fn bind_client(&self) -> Result<Endpoint> {
+        let builder = self.with_dns_server(self.with_relays(Endpoint::builder())?);
+        let builder = if self.local {
+            // mDNS always announces as well, there is no resolve only mode
+            builder.discovery_local_network()
+        } else {
+            // only resolve discovery, don't publish
+            let pkarr_relay = self.pkarr_relay.clone();
+            let builder = builder
+                .add_discovery(move |_| {
+                    Some(match pkarr_relay {
+                        Some(url) => PkarrResolver::new(url),
+                        None => PkarrResolver::n0_dns(),
+                    })
+                })
+                .add_discovery(|_| DhtDiscovery::builder().build().ok());
+            match self.dns_origin.clone() {
+                Some(origin) => builder.add_discovery(move |_| Some(DnsDiscovery::new(origin))),
+                None => builder,
+            }
+        };
+        Ok(self.with_address_book(builder).bind().await?)
+    }
+
+    /// The address to put into tickets, without direct addresses in relay-only mode
+    pub fn ticket_addr(&self, mut addr: NodeAddr) -> NodeAddr {
+        if self.relay_only {
+            addr.direct_addresses.clear();
+        }
+        addr
+    }
+
+    /// The command to look up the DNS record of a node
+    pub fn dig_command(&self, node_id: &NodeId) -> String {
+        let origin = self.dns_origin.as_deref().unwrap_or(N0_DNS_ORIGIN);
+        let server = match (self.dns_server, &self.dns_origin) {
+            (Some(server), _) => format!("@{} -p {} ", server.ip(), server.port()),
+            (None, None) => format!("@{} ", N0_DNS_ORIGIN),
+            (None, Some(_)) => String::new(),
+        };
+        format!(
+            "dig TXT {}_iroh.{}.{}",
+            server,
+            z32_node_id(node_id),
+            origin
+        )
+    }
+
+    fn with_relays(&self, builder: Builder) -> Result<Builder> {
+        anyhow::ensure!(
+            !(self.no_relay && (self.relay_only || !self.relays.is_empty())),
+            "--no-relay can not be combined with --relay or --relay-only"
+        );
+        let builder = if self.no_relay {
+            builder.relay_mode(RelayMode::Disabled)
+        } else if self.relays.is_empty() {
+            builder
+        } else {
+            let nodes = self.relays.iter().cloned().map(RelayNode::from);
+            builder.relay_mode(RelayMode::Custom(RelayMap::from_nodes(nodes)?))
+        };
+        self.with_path_selection(builder)
+    }
+
+    #[cfg(feature = "relay-only")]
+    fn with_path_selection(&self, builder: Builder) -> Result<Builder> {
+        use iroh::endpoint::PathSelection;
+
+        Ok(if self.relay_only {
+            builder.path_selection(PathSelection::RelayOnly)
+        } else {
+            builder
+        })
+    }
+
+    #[cfg(not(feature = "relay-only"))]
+    fn with_path_selection(&self, builder: Builder) -> Result<Builder> {
+        anyhow::ensure!(
+            !self.relay_only,
+            "--relay-only needs a build with `--features relay-only`"
+        );
+        Ok(builder)
+    }
+
+    fn with_dns_server(&self, builder: Builder) -> Builder {
+        match self.dns_server {
+            Some(server) => builder.dns_resolver(DnsResolver::with_nameserver(server)),
+            None => builder,
+        }
+    }
+
+    fn with_address_book(&self, builder: Builder) -> Builder {
+        match &self.address_book {
+            Some(address_book) => {
+                let address_book = address_book.clone();
+                builder.add_discovery(|_| Some(address_book))
+            }
+            None => builder,
+        }
+    }
+
+    /// Record the addresses we reached a node on in the address book, if any
+    pub fn record_connection(&self, ep: &Endpoint, node_id: NodeId) {
+        let (Some(address_book), Some(info)) = (&self.address_book, ep.remote_info(node_id)) else {
+            return;
+        };
+        let addr = NodeAddr::from_parts(
+            node_id,
+            info.relay_url.map(|relay| relay.relay_url),
+            info.addrs.into_iter().map(|addr| addr.addr),
+        );
+        if let Err(cause) = address_book.record(&addr) {
+            warn!("Failed to update the address book: {:?}", cause);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Round trip time and throughput measurements over an echo connection
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Context, Result};
+use bytes::Bytes;
+use iroh::endpoint::Connection;
+use tokio::io::AsyncReadExt;
+
+use common::framing;
+
+/// Summary of a set of round trip times
+#[derive(Debug)]
+pub struct RttStats {
+    pub count: usize,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    pub p99: Duration,
+}
+
+impl RttStats {
+    /// Compute the summary, returns `None` if there are no samples
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let count = sorted.len();
+        let total: Duration = sorted.iter().sum();
+        // nearest rank percentile
+        let p99_rank = (count * 99).div_ceil(100);
+        Some(Self {
+            count,
+            min: sorted[0],
+            avg: total / count as u32,
+            max: sorted[count - 1],
+            p99: sorted[p99_rank - 1],
+        })
+    }
+}
+
+impl fmt::Display for RttStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rtt min/avg/max/p99 = {:.2}/{:.2}/{:.2}/{:.2} ms over {} samples",
+            millis(self.min),
+            millis(self.avg),
+            millis(self.max),
+            millis(self.p99),
+            self.count
+        )
+    }
+}
+
+/// A duration in fractional milliseconds, for printing
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// A transfer rate in human readable form
+pub fn format_rate(bytes: u64, elapsed: Duration) -> String {
+    let rate = bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    if rate >= 1024.0 * 1024.0 {
+        format!("{:.2} MiB/s", rate / (1024.0 * 1024.0))
+    } else if rate >= 1024.0 {
+        format!("{:.2} KiB/s", rate / 1024.0)
+    } else {
+        format!("{:.0} B/s", rate)
+    }
+}
+
+/// Send `count` messages of `size` bytes, one after the other, and measure
+/// the round trip time of each of them
+pub async fn ping(
+    conn: &Connection,
+    count: usize,
+    size: usize,
+    interval: Duration,
+) -> Result<Vec<Duration>> {
+    let payload = vec![0xAB; size];
+    let mut rtts = Vec::with_capacity(count);
+    for seq in 0..count {
+        let start = Instant::now();
+        let res = framing::send_message(conn, &payload, size).await?;
+        let rtt = start.elapsed();
+        ensure!(res == payload, "echo does not match the message");
+        println!(
+            "{} bytes: seq={} time={:.2} ms",
+            res.len(),
+            seq,
+            millis(rtt)
+        );
+        rtts.push(rtt);
+        if seq + 1 < count {
+            tokio::time::sleep(interval).await;
+        }
+    }
+    Ok(rtts)
+}
+
+/// Stream `total` bytes through the echo service and measure how long it
+/// takes until all of them are echoed back
+///
+/// Returns the number of bytes echoed and the elapsed time.
+pub async fn throughput(conn: &Connection, total: u64) -> Result<(u64, Duration)> {
+    let start = Instant::now();
+    let reader = tokio::io::repeat(0xAB).take(total);
+    let (_, received) = framing::stream_through(conn, reader, tokio::io::sink()).await?;
+    let elapsed = start.elapsed();
+    ensure!(
+        received == total,
+        "received {} bytes, expected {}",
+        received,
+        total
+    );
+    Ok((received, elapsed))
+}
+
+/// Size of the datagram header, a `u32` sequence number and the `u64` send
+/// time in microseconds since the start of the probe
+const DATAGRAM_HEADER_SIZE: usize = 12;
+
+/// How long to wait for outstanding datagrams after the last one was sent
+const DATAGRAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Results of a datagram probe
+#[derive(Debug)]
+pub struct DatagramStats {
+    pub sent: usize,
+    pub received: usize,
+    /// Datagrams that arrived after a datagram with a higher sequence number
+    pub reordered: usize,
+    pub duplicates: usize,
+    pub rtts: Vec<Duration>,
+    seen: Vec<bool>,
+    highest: Option<u32>,
+}
+
+impl DatagramStats {
+    fn new(count: usize) -> Self {
+        Self {
+            sent: 0,
+            received: 0,
+            reordered: 0,
+            duplicates: 0,
+            rtts: Vec::with_capacity(count),
+            seen: vec![false; count],
+            highest: None,
+        }
+    }
+
+    fn record(&mut self, datagram: &[u8], now: Duration) {
+        if datagram.len() < DATAGRAM_HEADER_SIZE {
+            return;
+        }
+        let seq = u32::from_be_bytes(datagram[..4].try_into().unwrap());
+        let sent_micros = u64::from_be_bytes(datagram[4..12].try_into().unwrap());
+        let Some(seen) = self.seen.get_mut(seq as usize) else {
+            return;
+        };
+        if *seen {
+            self.duplicates += 1;
+            return;
+        }
+        *seen = true;
+        self.received += 1;
+        if self.highest.is_some_and(|highest| seq < highest) {
+            self.reordered += 1;
+        }
+        self.highest = self.highest.max(Some(seq));
+        self.rtts
+            .push(now.saturating_sub(Duration::from_micros(sent_micros)));
+    }
+
+    /// Fraction of sent datagrams that never came back
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        1.0 - self.received as f64 / self.sent as f64
+    }
+}
+
+impl fmt::Display for DatagramStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} datagrams sent, {} received, {:.1}% loss, {} reordered, {} duplicates",
+            self.sent,
+            self.received,
+            self.loss() * 100.0,
+            self.reordered,
+            self.duplicates
+        )?;
+        match RttStats::from_samples(&self.rtts) {
+            Some(stats) => write!(f, "{}", stats),
+            None => write!(f, "no datagrams received"),
+        }
+    }
+}
+
+/// Send `count` unreliable datagrams of `size` bytes and measure how many of
+/// them come back, in which order, and how long it takes
+pub async fn datagram_ping(
+    conn: &Connection,
+    count: usize,
+    size: usize,
+    interval: Duration,
+) -> Result<DatagramStats> {
+    let max_size = conn
+        .max_datagram_size()
+        .context("the connection does not support datagrams")?;
+    ensure!(
+        (DATAGRAM_HEADER_SIZE..=max_size).contains(&size),
+        "datagram size must be between {} and {} bytes",
+        DATAGRAM_HEADER_SIZE,
+        max_size
+    );
+    let count_u32 = u32::try_from(count).context("too many datagrams")?;
+
+    let start = Instant::now();
+    let mut stats = DatagramStats::new(count);
+    let sender = async {
+        let mut sent = 0;
+        for seq in 0..count_u32 {
+            let mut datagram = vec![0xAB; size];
+            datagram[..4].copy_from_slice(&seq.to_be_bytes());
+            let sent_micros = start.elapsed().as_micros() as u64;
+            datagram[4..12].copy_from_slice(&sent_micros.to_be_bytes());
+            conn.send_datagram(Bytes::from(datagram))?;
+            sent += 1;
+            tokio::time::sleep(interval).await;
+        }
+        anyhow::Ok(sent)
+    };
+    // Receive until all datagrams are back, or until the timeout after the
+    // last datagram was sent
+    let deadline = interval
+        .checked_mul(count_u32)
+        .and_then(|send_time| send_time.checked_add(DATAGRAM_TIMEOUT))
+        .and_then(|wait| tokio::time::Instant::from_std(start).checked_add(wait))
+        .context("the interval is too long for this many datagrams")?;
+    let receiver = tokio::time::timeout_at(deadline, async {
+        while let Ok(datagram) = conn.read_datagram().await {
+            stats.record(&datagram, start.elapsed());
+            if stats.received == count {
+                break;
+            }
+        }
+    });
+    let (sent, _) = tokio::join!(sender, receiver);
+    stats.sent = sent?;
+    Ok(stats)
+}
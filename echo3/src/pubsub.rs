@@ -0,0 +1,195 @@
+//! Group chat over iroh-gossip
+//!
+//! All nodes that join the same topic form a gossip swarm, and every message
+//! broadcast to the topic reaches all of them, not just the direct neighbors.
+//! A topic is either derived from a name, or taken from a pubsub ticket that
+//! also contains the addresses of nodes already in the swarm to bootstrap
+//! from.
+//!
+//! Gossip messages are relayed by other nodes, so every message is signed by
+//! its author, and messages with a bad signature are dropped.
+use std::{fmt, str::FromStr, time::Duration};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use iroh::{Endpoint, NodeAddr, NodeId, PublicKey, SecretKey};
+use iroh_base::{
+    ticket::{self, Ticket},
+    Signature,
+};
+use iroh_gossip::{
+    net::{Event, Gossip, GossipEvent},
+    proto::TopicId,
+};
+use n0_future::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::chat::stdin_lines;
+
+/// How long to wait for a connection to one of the bootstrap nodes
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A topic and the nodes to bootstrap from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PubsubTicket {
+    pub topic: TopicId,
+    pub nodes: Vec<NodeAddr>,
+}
+
+impl Ticket for PubsubTicket {
+    const KIND: &'static str = "pubsub";
+
+    fn to_bytes(&self) -> Vec<u8> {
+        postcard::to_stdvec(self).expect("serialization can not fail")
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ticket::Error> {
+        Ok(postcard::from_bytes(bytes)?)
+    }
+}
+
+impl fmt::Display for PubsubTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Ticket::serialize(self))
+    }
+}
+
+impl FromStr for PubsubTicket {
+    type Err = ticket::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ticket::deserialize(s)
+    }
+}
+
+/// Derive a topic id from a human readable name
+pub fn topic_from_name(name: &str) -> TopicId {
+    TopicId::from_bytes(Sha256::digest(name.as_bytes()).into())
+}
+
+/// A chat line, with a nonce so that repeated lines are not deduplicated
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    nonce: u64,
+    text: String,
+}
+
+/// A message together with its author and the author's signature
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedMessage {
+    from: PublicKey,
+    data: Vec<u8>,
+    signature: Signature,
+}
+
+impl SignedMessage {
+    fn sign(secret_key: &SecretKey, text: String) -> Result<Bytes> {
+        let message = Message {
+            nonce: rand::random(),
+            text,
+        };
+        let data = postcard::to_stdvec(&message)?;
+        let signature = secret_key.sign(&data);
+        let signed = Self {
+            from: secret_key.public(),
+            data,
+            signature,
+        };
+        Ok(postcard::to_stdvec(&signed)?.into())
+    }
+
+    fn verify(bytes: &[u8]) -> Result<(NodeId, Message)> {
+        let signed: Self = postcard::from_bytes(bytes)?;
+        signed
+            .from
+            .verify(&signed.data, &signed.signature)
+            .context("invalid signature")?;
+        let message = postcard::from_bytes(&signed.data)?;
+        Ok((signed.from, message))
+    }
+}
+
+/// Join a topic and chat until stdin is closed or Ctrl+C is pressed
+///
+/// The addresses of the bootstrap nodes are added to the endpoint, so nodes
+/// can be reached without any discovery service.
+pub async fn run(
+    ep: &Endpoint,
+    gossip: &Gossip,
+    topic: TopicId,
+    bootstrap: Vec<NodeAddr>,
+) -> Result<()> {
+    let mut bootstrap_ids = Vec::with_capacity(bootstrap.len());
+    for addr in bootstrap {
+        bootstrap_ids.push(addr.node_id);
+        if !addr.is_empty() {
+            ep.add_node_addr(addr)?;
+        }
+    }
+    let has_bootstrap = !bootstrap_ids.is_empty();
+    let (sender, mut receiver) = gossip.subscribe(topic, bootstrap_ids)?.split();
+    if has_bootstrap {
+        println!("Joining the swarm...");
+        tokio::select! {
+            res = tokio::time::timeout(JOIN_TIMEOUT, receiver.joined()) => {
+                res.with_context(|| {
+                    format!("could not reach any bootstrap node within {:?}", JOIN_TIMEOUT)
+                })??;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                println!("Leaving the topic");
+                return Ok(());
+            }
+        }
+        println!("Joined the swarm");
+    } else {
+        println!("Waiting for other nodes to join");
+    }
+    println!("Type a line and press enter to send, Ctrl+D to leave.");
+
+    let send = async {
+        let mut lines = stdin_lines();
+        while let Some(line) = lines.recv().await {
+            sender
+                .broadcast(SignedMessage::sign(ep.secret_key(), line)?)
+                .await?;
+        }
+        anyhow::Ok(())
+    };
+    let recv = async {
+        while let Some(event) = receiver.next().await {
+            match event? {
+                Event::Gossip(GossipEvent::Received(msg)) => {
+                    match SignedMessage::verify(&msg.content) {
+                        Ok((from, message)) => println!("{}: {}", from.fmt_short(), message.text),
+                        Err(cause) => {
+                            println!(
+                                "Dropped a message from {}: {:#}",
+                                msg.delivered_from.fmt_short(),
+                                cause
+                            )
+                        }
+                    }
+                }
+                Event::Gossip(GossipEvent::NeighborUp(node_id)) => {
+                    println!("{} is now a neighbor", node_id.fmt_short())
+                }
+                Event::Gossip(GossipEvent::NeighborDown(node_id)) => {
+                    println!("{} is no longer a neighbor", node_id.fmt_short())
+                }
+                Event::Gossip(GossipEvent::Joined(_)) => {}
+                Event::Lagged => println!("Missed some messages, we are too slow"),
+            }
+        }
+        anyhow::Ok(())
+    };
+
+    tokio::select! {
+        res = send => res?,
+        res = recv => res?,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    println!("Leaving the topic");
+    Ok(())
+}
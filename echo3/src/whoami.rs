@@ -0,0 +1,82 @@
+//! "What is my address" service, built on the typed RPC layer
+//!
+//! Tells a client its node id, the address the server observes it on and
+//! the path type in use, like STUN does for plain UDP. The observed address
+//! is only known for direct paths. For relayed paths the server only knows
+//! the relay.
+use std::net::SocketAddr;
+
+use common::rpc::{RemoteError, RpcClient, Service};
+use iroh::{endpoint::ConnectionType, Endpoint, NodeId, RelayUrl};
+use serde::{Deserialize, Serialize};
+
+/// The ALPN protocol identifier for the whoami service
+pub const WHOAMI_ALPN: &[u8] = b"ECHO-WHOAMI";
+
+/// The only request of the whoami service
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WhoamiRequest;
+
+/// The path the server uses to talk to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathType {
+    Direct,
+    Relay,
+    Mixed,
+    Unknown,
+}
+
+/// How the server sees the client
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Observed {
+    pub node_id: NodeId,
+    /// The address the client was seen on, for direct and mixed paths
+    pub addr: Option<SocketAddr>,
+    /// The relay the client was reached through, for relayed and mixed paths
+    pub relay_url: Option<RelayUrl>,
+    pub path: PathType,
+}
+
+/// Whoami service, looks up the path to the client on the server endpoint
+#[derive(Debug)]
+pub struct WhoamiService {
+    endpoint: Endpoint,
+}
+
+impl WhoamiService {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self { endpoint }
+    }
+}
+
+/// Client stub for the whoami service
+pub type WhoamiClient = RpcClient<WhoamiService>;
+
+impl Service for WhoamiService {
+    type Request = WhoamiRequest;
+    type Response = Observed;
+
+    async fn handle(
+        &self,
+        remote: NodeId,
+        _request: WhoamiRequest,
+    ) -> Result<Observed, RemoteError> {
+        let conn_type = self
+            .endpoint
+            .remote_info(remote)
+            .map(|info| info.conn_type)
+            .unwrap_or(ConnectionType::None);
+        let (path, addr, relay_url) = match conn_type {
+            ConnectionType::Direct(addr) => (PathType::Direct, Some(addr), None),
+            ConnectionType::Relay(url) => (PathType::Relay, None, Some(url)),
+            ConnectionType::Mixed(addr, url) => (PathType::Mixed, Some(addr), Some(url)),
+            ConnectionType::None => (PathType::Unknown, None, None),
+        };
+        Ok(Observed {
+            node_id: remote,
+            addr,
+            relay_url,
+            path,
+        })
+    }
+}
@@ -0,0 +1,145 @@
+//! Configuration file of the node
+//!
+//! A TOML file, all keys are optional:
+//!
+//! ```toml
+//! # Identity profile, see `node key`. Without it the node uses IROH_SECRET
+//! # or IROH_PROFILE, or a new random key on every start.
+//! profile = "server"
+//! # Where the blob store keeps its data
+//! data_dir = ".node-data"
+//!
+//! [echo]
+//! enabled = true
+//! max_message_size = 1048576
+//! max_connections = 1000
+//! max_connections_per_node = 10
+//! max_streams_per_connection = 100
+//! bytes_per_second = 1048576
+//!
+//! [blobs]
+//! enabled = true
+//! # Files and directories to share on every start
+//! share = ["files"]
+//!
+//! [tracker]
+//! enabled = false
+//! ```
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use common::{framing, limits::Limits};
+
+/// Data directory if none is configured
+const DEFAULT_DATA_DIR: &str = ".node-data";
+
+/// Protocols that can be enabled and disabled
+pub const PROTOCOLS: [&str; 3] = ["echo", "blobs", "tracker"];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Name of the identity profile
+    pub profile: Option<String>,
+    /// Directory for persistent data
+    pub data_dir: Option<PathBuf>,
+    pub echo: EchoConfig,
+    pub blobs: BlobsConfig,
+    pub tracker: TrackerConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EchoConfig {
+    pub enabled: bool,
+    /// Maximum size of a single framed message
+    pub max_message_size: usize,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_node: Option<usize>,
+    pub max_streams_per_connection: Option<usize>,
+    pub bytes_per_second: Option<u64>,
+}
+
+impl Default for EchoConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_message_size: framing::DEFAULT_MAX_MESSAGE_SIZE,
+            max_connections: None,
+            max_connections_per_node: None,
+            max_streams_per_connection: None,
+            bytes_per_second: None,
+        }
+    }
+}
+
+impl EchoConfig {
+    /// The configured limits of the echo service
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_connections: self.max_connections,
+            max_connections_per_node: self.max_connections_per_node,
+            max_streams_per_connection: self.max_streams_per_connection,
+            bytes_per_second: self.bytes_per_second,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlobsConfig {
+    pub enabled: bool,
+    /// Files and directories to import and share on every start
+    pub share: Vec<PathBuf>,
+}
+
+impl Default for BlobsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            share: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrackerConfig {
+    pub enabled: bool,
+}
+
+impl Config {
+    /// Load the configuration from a TOML file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Enable or disable one of the [`PROTOCOLS`], overriding the file
+    pub fn set_enabled(&mut self, protocol: &str, enabled: bool) -> Result<()> {
+        match protocol {
+            "echo" => self.echo.enabled = enabled,
+            "blobs" => self.blobs.enabled = enabled,
+            "tracker" => self.tracker.enabled = enabled,
+            _ => bail!(
+                "unknown protocol: {}, expected one of {}",
+                protocol,
+                PROTOCOLS.join(", ")
+            ),
+        }
+        Ok(())
+    }
+
+    /// Directory for persistent data
+    pub fn data_dir(&self) -> PathBuf {
+        self.data_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR))
+    }
+}
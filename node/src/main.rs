@@ -0,0 +1,150 @@
+use std::{env, path::PathBuf, process};
+
+use anyhow::Result;
+use common::{cli, collection, echo, keys};
+use config::Config;
+use iroh::{protocol::Router, Endpoint};
+use iroh_base::ticket::NodeTicket;
+use iroh_blobs::{net_protocol::Blobs, store::fs::FsStore, ticket::BlobTicket};
+
+mod config;
+mod tracker;
+
+/// Run mode - serves all enabled protocols from one endpoint
+///
+/// All protocols share the endpoint, and so the node id, and are registered
+/// on one router. Runs until Ctrl+C is pressed.
+async fn run(config: Config) -> Result<()> {
+    anyhow::ensure!(
+        config.echo.enabled || config.blobs.enabled || config.tracker.enabled,
+        "all protocols are disabled"
+    );
+
+    // Get the secret key from the configured profile, or the environment
+    let secret_key = match &config.profile {
+        Some(profile) => keys::load_or_create_profile(profile)?,
+        None => keys::get_or_generate_secret_key()?,
+    };
+
+    // Create an endpoint and print the node ID
+    let ep = Endpoint::builder()
+        .discovery_n0()
+        .discovery_dht()
+        .secret_key(secret_key)
+        .bind()
+        .await?;
+
+    let node_id = ep.node_id();
+    let addr = ep.node_addr().await?;
+
+    println!("Node ID: {}", node_id);
+    println!("Full address: {:?}", addr);
+    println!("Ticket: {}", NodeTicket::from(addr.clone()));
+
+    let mut builder = Router::builder(ep.clone());
+    let mut alpns: Vec<&[u8]> = Vec::new();
+    if config.echo.enabled {
+        let echo =
+            echo::EchoProtocol::new(config.echo.max_message_size).with_limits(config.echo.limits());
+        for alpn in echo::server_alpns(&echo::Version::ALL) {
+            builder = builder.accept(alpn, echo.clone());
+            alpns.push(alpn);
+        }
+        println!("Serving echo");
+    }
+
+    // The tags keep the shared content from being garbage collected
+    let mut store = None;
+    let mut tags = Vec::new();
+    if config.blobs.enabled {
+        let blobs = FsStore::load(config.data_dir().join("blobs")).await?;
+        for path in &config.blobs.share {
+            let tag = collection::import(path.clone(), &blobs).await?;
+            let ticket = BlobTicket::new(addr.clone(), *tag.hash(), tag.format());
+            println!("Sharing {}: {}", path.display(), ticket);
+            tags.push(tag);
+        }
+        builder = builder.accept(iroh_blobs::ALPN, Blobs::new(&blobs, ep.clone(), None));
+        alpns.push(iroh_blobs::ALPN);
+        println!("Serving blobs");
+        store = Some(blobs);
+    }
+
+    if config.tracker.enabled {
+        builder = builder.accept(tracker::TRACKER_ALPN, tracker::TrackerProtocol::default());
+        alpns.push(tracker::TRACKER_ALPN);
+        println!(
+            "Serving a content discovery tracker, use sendme4 --tracker {}",
+            node_id
+        );
+    }
+
+    let router = builder.spawn();
+    echo::prefer_newest(&ep, &alpns)?;
+    println!("Node is running. Press Ctrl+C to stop...");
+
+    // Wait for Ctrl-C
+    tokio::signal::ctrl_c().await?;
+    println!("\nReceived Ctrl+C, shutting down...");
+
+    // Gracefully shut down the router
+    router.shutdown().await?;
+
+    // Shutdown the store to sync to disk
+    drop(tags);
+    if let Some(store) = store {
+        store.shutdown().await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Initialize tracing with default configuration
+    tracing_subscriber::fmt::init();
+
+    let mut args: Vec<String> = env::args().collect();
+    let config_path = cli::take_option(&mut args, "--config")?.map(PathBuf::from);
+    let mut enable = Vec::new();
+    while let Some(protocol) = cli::take_option(&mut args, "--enable")? {
+        enable.push(protocol);
+    }
+    let mut disable = Vec::new();
+    while let Some(protocol) = cli::take_option(&mut args, "--disable")? {
+        disable.push(protocol);
+    }
+    let cmd = args.get(1).map(|x| x.to_lowercase()).unwrap_or_default();
+    match cmd.as_str() {
+        "run" if args.len() == 2 => {
+            // Run the node with the configured protocols
+            let mut config = match config_path {
+                Some(path) => Config::load(&path)?,
+                None => Config::default(),
+            };
+            for protocol in enable {
+                config.set_enabled(&protocol, true)?;
+            }
+            for protocol in disable {
+                config.set_enabled(&protocol, false)?;
+            }
+            run(config).await
+        }
+        "key" => {
+            // Manage named identity profiles
+            keys::key_command(&args[2..])
+        }
+        _ => {
+            println!("Usage: node <command> [args]");
+            println!("Commands:");
+            println!("  run                    Serve echo, blobs and a tracker from one node");
+            println!("  key <command>          Manage identity profiles");
+            println!("Options:");
+            println!("  --config <file>        Read the configuration from a TOML file");
+            println!("  --enable <protocol>    Enable a protocol, can be repeated");
+            println!("  --disable <protocol>   Disable a protocol, can be repeated");
+            println!("Protocols: {}", config::PROTOCOLS.join(", "));
+            process::exit(1);
+        }
+    }
+}
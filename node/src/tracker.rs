@@ -0,0 +1,239 @@
+//! A minimal content discovery tracker
+//!
+//! Speaks the protocol of iroh-content-discovery, so sendme4 and other
+//! clients can announce content to the node and ask it for providers. Every
+//! request is on its own bi-directional stream and is handled in its own
+//! task. The protocol has no error response, so a stream with a malformed
+//! request or an announce with a bad signature is reset, without affecting
+//! the other streams of the connection. Announces are kept in memory, the
+//! newest one of every host for every content. Announces that are not
+//! renewed within [`ANNOUNCE_TTL`] expire, and the number of hosts per content
+//! and of contents per host is capped.
+//!
+//! Unlike the full tracker, this one does not probe the hosts, so the
+//! `verified` query flag is ignored.
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use iroh::{
+    endpoint::{Connection, ConnectionError, RecvStream, SendStream, VarInt},
+    protocol::ProtocolHandler,
+    NodeId,
+};
+use iroh_content_discovery::protocol::{
+    AnnounceKind, Query, QueryResponse, Request, Response, SignedAnnounce,
+};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+/// The ALPN protocol identifier of the tracker
+pub use iroh_content_discovery::protocol::ALPN as TRACKER_ALPN;
+
+/// Maximum size of a single request
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// Error code to reset a stream with if its request can not be handled
+const BAD_REQUEST: u32 = 1;
+
+/// How long an announce is kept after it was received, clients renew theirs
+/// every 30 seconds
+const ANNOUNCE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How often expired announces are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum number of hosts kept for a single content
+const MAX_HOSTS_PER_CONTENT: usize = 1000;
+
+/// Maximum number of contents a single host can announce
+const MAX_CONTENTS_PER_HOST: usize = 1000;
+
+/// An announce and when it was received
+#[derive(Debug)]
+struct Entry {
+    announce: SignedAnnounce,
+    received: Instant,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.received) > ANNOUNCE_TTL
+    }
+}
+
+#[derive(Debug)]
+struct Announces {
+    /// Announces by content, and by host within the content
+    by_content: HashMap<String, HashMap<NodeId, Entry>>,
+    /// Number of contents announced by every host
+    per_host: HashMap<NodeId, usize>,
+    last_prune: Instant,
+}
+
+impl Default for Announces {
+    fn default() -> Self {
+        Self {
+            by_content: HashMap::new(),
+            per_host: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+}
+
+impl Announces {
+    /// Remove expired announces, at most once every [`PRUNE_INTERVAL`]
+    fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.last_prune) < PRUNE_INTERVAL {
+            return;
+        }
+        self.last_prune = now;
+        let per_host = &mut self.per_host;
+        self.by_content.retain(|_, hosts| {
+            hosts.retain(|host, entry| {
+                if !entry.is_expired(now) {
+                    return true;
+                }
+                if let Some(count) = per_host.get_mut(host) {
+                    *count -= 1;
+                    if *count == 0 {
+                        per_host.remove(host);
+                    }
+                }
+                false
+            });
+            !hosts.is_empty()
+        });
+    }
+}
+
+/// Tracker protocol handler
+#[derive(Debug, Clone, Default)]
+pub struct TrackerProtocol {
+    announces: Arc<Mutex<Announces>>,
+}
+
+impl TrackerProtocol {
+    fn announce(&self, announce: SignedAnnounce) -> Result<()> {
+        announce.verify()?;
+        let now = Instant::now();
+        let mut announces = self.announces.lock().unwrap();
+        announces.prune(now);
+        let Announces {
+            by_content,
+            per_host,
+            ..
+        } = &mut *announces;
+        let content = announce.content.to_string();
+        let host_count = per_host.get(&announce.host).copied().unwrap_or_default();
+        let hosts = by_content.get(&content);
+        let known = hosts.and_then(|hosts| hosts.get(&announce.host));
+        match known {
+            // Announces can arrive out of order, keep the newest one
+            Some(entry) if entry.announce.timestamp >= announce.timestamp => return Ok(()),
+            Some(_) => {}
+            None if hosts.is_some_and(|hosts| hosts.len() >= MAX_HOSTS_PER_CONTENT) => {
+                bail!("too many hosts for {}", announce.content)
+            }
+            None if host_count >= MAX_CONTENTS_PER_HOST => {
+                bail!("too many contents announced by {}", announce.host)
+            }
+            None => *per_host.entry(announce.host).or_default() += 1,
+        }
+        info!("{} announced {}", announce.host, announce.content);
+        by_content.entry(content).or_default().insert(
+            announce.host,
+            Entry {
+                announce,
+                received: now,
+            },
+        );
+        Ok(())
+    }
+
+    fn query(&self, query: &Query) -> Vec<SignedAnnounce> {
+        let now = Instant::now();
+        let announces = self.announces.lock().unwrap();
+        let Some(hosts) = announces.by_content.get(&query.content.to_string()) else {
+            return Vec::new();
+        };
+        hosts
+            .values()
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| &entry.announce)
+            .filter(|announce| {
+                !query.flags.complete || matches!(announce.kind, AnnounceKind::Complete)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Handle the request on a single stream
+    async fn handle_stream(
+        &self,
+        remote: NodeId,
+        send_stream: &mut SendStream,
+        recv_stream: &mut RecvStream,
+    ) -> Result<()> {
+        let request = recv_stream.read_to_end(MAX_REQUEST_SIZE).await?;
+        match postcard::from_bytes(&request)? {
+            Request::Announce(announce) => self.announce(announce)?,
+            Request::Query(query) => {
+                let hosts = self.query(&query);
+                info!(
+                    "{} asked for {}, {} hosts",
+                    remote,
+                    query.content,
+                    hosts.len()
+                );
+                let response = Response::QueryResponse(QueryResponse { hosts });
+                send_stream
+                    .write_all(&postcard::to_stdvec(&response)?)
+                    .await?;
+            }
+        }
+        send_stream.finish()?;
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for TrackerProtocol {
+    fn accept(
+        &self,
+        conn: Connection,
+    ) -> Pin<Box<dyn std::future::Future<Output = Result<()>> + Send + 'static>> {
+        let tracker = self.clone();
+        Box::pin(async move {
+            let remote = conn.remote_node_id()?;
+            info!("Tracker connection accepted from {}", remote);
+            let mut tasks = JoinSet::new();
+            let res: Result<()> = loop {
+                let (mut send_stream, mut recv_stream) = match conn.accept_bi().await {
+                    Ok(streams) => streams,
+                    Err(ConnectionError::ApplicationClosed(_)) => break Ok(()),
+                    Err(cause) => break Err(cause.into()),
+                };
+                let tracker = tracker.clone();
+                tasks.spawn(async move {
+                    if let Err(cause) = tracker
+                        .handle_stream(remote, &mut send_stream, &mut recv_stream)
+                        .await
+                    {
+                        warn!("Bad request from {}: {:?}", remote, cause);
+                        send_stream.reset(VarInt::from_u32(BAD_REQUEST)).ok();
+                        recv_stream.stop(VarInt::from_u32(BAD_REQUEST)).ok();
+                    }
+                });
+            };
+
+            // Let requests in flight finish, also if the connection failed
+            while tasks.join_next().await.is_some() {}
+
+            res
+        })
+    }
+}
@@ -1,10 +1,9 @@
 use std::{env, path::PathBuf, process, str::FromStr};
 
 use anyhow::{ensure, Context, Result};
+use common::{cli, diag, dial, keys};
 use iroh::{protocol::Router, Endpoint};
-use iroh_blobs::{
-    net_protocol::Blobs, store::fs::FsStore, ticket::BlobTicket,
-};
+use iroh_blobs::{net_protocol::Blobs, store::fs::FsStore, ticket::BlobTicket};
 use tracing::info;
 use util::{crate_name, create_recv_dir, create_send_dir};
 
@@ -27,7 +26,7 @@ async fn share(path: PathBuf) -> Result<()> {
     );
 
     // Get or generate a secret key
-    let secret_key = util::get_or_generate_secret_key()?;
+    let secret_key = keys::get_or_generate_secret_key()?;
 
     // Create a blob store
     let blobs_path = create_send_dir()?;
@@ -72,7 +71,14 @@ async fn share(path: PathBuf) -> Result<()> {
 }
 
 /// Client mode - receives a file
-async fn receive(target: &str, ticket: &str) -> Result<()> {
+///
+/// With `diag`, prints path changes and the connection stats.
+async fn receive(
+    target: &str,
+    ticket: &str,
+    diag: bool,
+    dial_options: &dial::DialOptions,
+) -> Result<()> {
     let target = PathBuf::from(target);
     // Parse the address using NodeTicket
     let ticket = BlobTicket::from_str(ticket).context("invalid address")?;
@@ -91,14 +97,21 @@ async fn receive(target: &str, ticket: &str) -> Result<()> {
 
     // Connect to the node
     info!("Connecting to: {:?}", ticket.node_addr());
-    let conn = ep
-        .connect(ticket.node_addr().clone(), iroh_blobs::ALPN)
-        .await?;
+    let diag_task = diag.then(|| diag::watch_paths(ep.clone()));
+    let conn = dial::connect(
+        &ep,
+        ticket.node_addr().clone(),
+        &[iroh_blobs::ALPN],
+        dial_options,
+    )
+    .await?;
     info!("Getting blob");
-    let stats = store
-        .remote()
-        .fetch(conn, ticket.clone())
-        .await?;
+    let stats = store.remote().fetch(conn.clone(), ticket.clone()).await?;
+    if let Some(diag_task) = diag_task {
+        // Stop printing path changes, so they do not mix with the statistics
+        diag_task.abort();
+        diag::print_stats(&conn.stats());
+    }
     info!("Exporting file");
     let size = store.export(ticket.hash(), target.clone()).await?;
     info!("Exported file to {} with size: {}", target.display(), size);
@@ -119,7 +132,9 @@ async fn main() -> Result<()> {
     // Initialize tracing with default configuration
     tracing_subscriber::fmt::init();
 
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let diag = cli::take_flag(&mut args, "--diag");
+    let dial_options = dial::DialOptions::from_args(&mut args)?;
     let cmd = args.get(1).map(|x| x.to_lowercase()).unwrap_or_default();
     match cmd.as_str() {
         "share" if args.len() == 3 => {
@@ -131,13 +146,23 @@ async fn main() -> Result<()> {
             // Client mode - receive a file or directory
             let path = &args[2];
             let ticket = &args[3];
-            receive(path, ticket).await
+            receive(path, ticket, diag, &dial_options).await
+        }
+        "key" => {
+            // Manage named identity profiles
+            keys::key_command(&args[2..])
         }
         _ => {
             println!("Usage: {} <command> [args]", crate_name());
             println!("Commands:");
             println!("  share <file_path>             Share a file");
             println!("  receive <file_path> <ticket>  Receive a directory");
+            println!("  key <command>                 Manage identity profiles");
+            println!("Options:");
+            println!("  --diag                        Print path changes and connection stats");
+            println!("  --dial-timeout <ms>           Time a single dial may take (default 10000)");
+            println!("  --max-attempts <n>            Dial attempts before giving up (default 5)");
+            println!("  --backoff <ms>                Wait after a failed dial (default 500)");
             process::exit(1);
         }
     }
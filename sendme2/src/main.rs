@@ -1,6 +1,7 @@
 use std::{env, ops::Deref, path::PathBuf, process, str::FromStr};
 
 use anyhow::{ensure, Context, Result};
+use common::{cli, collection, diag, dial, keys};
 use iroh::{protocol::Router, Endpoint};
 use iroh_blobs::{
     format::collection::Collection, net_protocol::Blobs, store::fs::FsStore, ticket::BlobTicket,
@@ -27,7 +28,7 @@ async fn share(path: PathBuf) -> Result<()> {
     );
 
     // Get or generate a secret key
-    let secret_key = util::get_or_generate_secret_key()?;
+    let secret_key = keys::get_or_generate_secret_key()?;
 
     // Create a blob store
     let blobs_path = create_send_dir()?;
@@ -42,7 +43,7 @@ async fn share(path: PathBuf) -> Result<()> {
     println!("Node ID: {}", node_id);
     println!("Full address: {:?}", addr);
 
-    let tag = util::import(absolute_path.clone(), &blobs).await?;
+    let tag = collection::import(absolute_path.clone(), &blobs).await?;
     let ticket = BlobTicket::new(addr, *tag.hash(), tag.format());
     println!("Sharing {}", absolute_path.display());
     println!("Hash: {}", tag.hash());
@@ -74,7 +75,9 @@ async fn share(path: PathBuf) -> Result<()> {
 }
 
 /// Client mode - receives a file
-async fn receive(ticket: &str) -> Result<()> {
+///
+/// With `diag`, prints path changes and the connection stats.
+async fn receive(ticket: &str, diag: bool, dial_options: &dial::DialOptions) -> Result<()> {
     // Parse the address using NodeTicket
     let ticket = BlobTicket::from_str(ticket).context("invalid address")?;
 
@@ -89,18 +92,25 @@ async fn receive(ticket: &str) -> Result<()> {
 
     // Connect to the node
     info!("Connecting to: {:?}", ticket.node_addr());
-    let conn = ep
-        .connect(ticket.node_addr().clone(), iroh_blobs::ALPN)
-        .await?;
+    let diag_task = diag.then(|| diag::watch_paths(ep.clone()));
+    let conn = dial::connect(
+        &ep,
+        ticket.node_addr().clone(),
+        &[iroh_blobs::ALPN],
+        dial_options,
+    )
+    .await?;
     info!("Getting hash sequence");
-    let stats = store
-        .remote()
-        .fetch(conn, ticket.clone())
-        .await?;
+    let stats = store.remote().fetch(conn.clone(), ticket.clone()).await?;
+    if let Some(diag_task) = diag_task {
+        // Stop printing path changes, so they do not mix with the statistics
+        diag_task.abort();
+        diag::print_stats(&conn.stats());
+    }
     println!("Transfer stats: {:?}", stats);
     info!("Exporting file");
     let collection = Collection::load(ticket.hash(), store.deref()).await?;
-    util::export(&store, collection).await?;
+    collection::export(&store, collection).await?;
 
     // close the endpoint, just to be nice
     ep.close().await;
@@ -116,7 +126,9 @@ async fn main() -> Result<()> {
     // Initialize tracing with default configuration
     tracing_subscriber::fmt::init();
 
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let diag = cli::take_flag(&mut args, "--diag");
+    let dial_options = dial::DialOptions::from_args(&mut args)?;
     let cmd = args.get(1).map(|x| x.to_lowercase()).unwrap_or_default();
     match cmd.as_str() {
         "share" if args.len() == 3 => {
@@ -127,13 +139,24 @@ async fn main() -> Result<()> {
         "receive" | "recv" if args.len() == 3 => {
             // Client mode - receive a file or directory
             let ticket = &args[2];
-            receive(ticket).await
+            receive(ticket, diag, &dial_options).await
+        }
+        "key" => {
+            // Manage named identity profiles
+            keys::key_command(&args[2..])
         }
         _ => {
             println!("Usage: {} <command> [args]", crate_name());
             println!("Commands:");
             println!("  share <dir_path>   Share a directory");
             println!("  receive <ticket>   Receive a directory");
+            println!("  key <command>      Manage identity profiles");
+            println!("Options:");
+            println!("  --diag             Print path changes and connection stats");
+            println!("  --dial-timeout <ms>");
+            println!("                     Time a single dial may take (default 10000)");
+            println!("  --max-attempts <n> Dial attempts before giving up (default 5)");
+            println!("  --backoff <ms>     Wait after a failed dial (default 500)");
             process::exit(1);
         }
     }
@@ -1,12 +1,11 @@
 use std::{collections::BTreeSet, env, ops::Deref, path::PathBuf, process, str::FromStr};
 
 use anyhow::{ensure, Context, Result};
+use common::{cli, collection, diag, keys};
 use futures::StreamExt;
 use iroh::{protocol::Router, Endpoint};
 use iroh_blobs::{
-    api::{
-        downloader::{DownloadOptions, Shuffled, SplitStrategy},
-    },
+    api::downloader::{DownloadOptions, Shuffled, SplitStrategy},
     format::collection::Collection,
     net_protocol::Blobs,
     store::fs::FsStore,
@@ -34,7 +33,7 @@ async fn share(path: PathBuf) -> Result<()> {
     );
 
     // Get or generate a secret key
-    let secret_key = util::get_or_generate_secret_key()?;
+    let secret_key = keys::get_or_generate_secret_key()?;
 
     // Create a blob store
     let blobs_path = create_send_dir()?;
@@ -49,7 +48,7 @@ async fn share(path: PathBuf) -> Result<()> {
     println!("Node ID: {}", node_id);
     println!("Full address: {:?}", addr);
 
-    let tag = util::import(absolute_path.clone(), &blobs).await?;
+    let tag = collection::import(absolute_path.clone(), &blobs).await?;
     let ticket = BlobTicket::new(addr, *tag.hash(), tag.format());
     println!("Sharing {}", absolute_path.display());
     println!("Hash: {}", tag.hash());
@@ -89,7 +88,11 @@ async fn share(path: PathBuf) -> Result<()> {
 }
 
 /// Client mode - receives a file
-async fn receive(tickets: Vec<String>) -> Result<()> {
+///
+/// With `diag`, prints the path to every provider whenever it changes. The
+/// connections are owned by the downloader, so there are no connection
+/// statistics to print at the end.
+async fn receive(tickets: Vec<String>, diag: bool) -> Result<()> {
     // Parse the addresses using NodeTicket
     let tickets = tickets
         .iter()
@@ -121,6 +124,7 @@ async fn receive(tickets: Vec<String>) -> Result<()> {
 
     // Create an endpoint
     let ep = Endpoint::builder().bind().await?;
+    let diag_task = diag.then(|| diag::watch_paths(ep.clone()));
 
     // add the connection information contained in the tickets to the endpoint
     for ticket in tickets {
@@ -143,8 +147,11 @@ async fn receive(tickets: Vec<String>) -> Result<()> {
     }
     info!("Exporting file");
     let collection = Collection::load(content.hash, store.deref()).await?;
-    util::export(&store, collection).await?;
+    collection::export(&store, collection).await?;
 
+    if let Some(diag_task) = diag_task {
+        diag_task.abort();
+    }
     // close the endpoint, just to be nice
     ep.close().await;
     // shutdown the store to sync to disk
@@ -159,7 +166,8 @@ async fn main() -> Result<()> {
     // Initialize tracing with default configuration
     tracing_subscriber::fmt::init();
 
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let diag = cli::take_flag(&mut args, "--diag");
     let cmd = args.get(1).map(|x| x.to_lowercase()).unwrap_or_default();
     match cmd.as_str() {
         "share" if args.len() == 3 => {
@@ -170,13 +178,21 @@ async fn main() -> Result<()> {
         "receive" | "recv" if args.len() >= 3 => {
             // Client mode - receive a file or directory
             let tickets = args.iter().skip(2).cloned().collect::<Vec<_>>();
-            receive(tickets).await
+            receive(tickets, diag).await
+        }
+        "key" => {
+            // Manage named identity profiles
+            keys::key_command(&args[2..])
         }
         _ => {
             println!("Usage: sendme2 <command> [args]");
             println!("Commands:");
             println!("  share <dir_path>   Share a directory");
             println!("  receive <ticket>   Receive a directory");
+            println!("  key <command>      Manage identity profiles");
+            println!("Options:");
+            println!("  --diag             Print path changes of the connections, without");
+            println!("                     statistics, the downloader owns the connections");
             process::exit(1);
         }
     }
@@ -1,35 +1,42 @@
-use std::{env, ops::Deref, path::PathBuf, process, str::FromStr, time::Duration};
+use std::{
+    env, net::SocketAddr, ops::Deref, path::PathBuf, process, str::FromStr, sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{ensure, Context, Result};
+use common::{access, cli, collection, diag, keys};
 use futures::StreamExt;
 use iroh::{discovery, protocol::Router, Endpoint, NodeId, SecretKey};
 use iroh_blobs::{
-    api::{
-        downloader::{DownloadOptions, SplitStrategy},
-    },
+    api::downloader::{DownloadOptions, SplitStrategy},
     format::collection::Collection,
     net_protocol::Blobs,
     store::fs::FsStore,
     ticket::BlobTicket,
     HashAndFormat,
 };
-use iroh_content_discovery::protocol::{
-    AbsoluteTime, Announce, AnnounceKind, SignedAnnounce,
-};
+use iroh_content_discovery::protocol::{AbsoluteTime, Announce, AnnounceKind, SignedAnnounce};
+use iroh_metrics::Registry;
 use tracing::{info, trace, warn};
 use util::{create_recv_dir, create_send_dir, TrackerDiscovery};
 
+mod metrics;
+mod ticket;
 mod util;
 
-/// node ticket for the tracker
+/// node id of the default tracker, override with `--tracker`
 /// local
 /// const TRACKER: &str = "b223f67b76e1853c7f76d9a9f8ce4d8dbb04a48ad9631ce52347043388475767";
 /// arqu
 const TRACKER: &str = "69b2f535d5792b50599b51990963e0cca1041679cd968563a8bc3179a7c42e67";
 
 /// periodically announce the content to the tracker
-async fn announce_task(content: HashAndFormat, ep: Endpoint, secret_key: SecretKey) -> Result<()> {
-    let tracker: NodeId = TRACKER.parse()?;
+async fn announce_task(
+    content: HashAndFormat,
+    ep: Endpoint,
+    secret_key: SecretKey,
+    tracker: NodeId,
+) -> Result<()> {
     let content = content.to_string();
     loop {
         let announce = Announce {
@@ -40,9 +47,7 @@ async fn announce_task(content: HashAndFormat, ep: Endpoint, secret_key: SecretK
         };
         let signed_announce = SignedAnnounce::new(announce, &secret_key)?;
         println!("Announcing: {:?}", signed_announce);
-        if let Err(cause) =
-            iroh_content_discovery::announce(&ep, tracker, signed_announce).await
-        {
+        if let Err(cause) = iroh_content_discovery::announce(&ep, tracker, signed_announce).await {
             warn!("Failed to send announce {:?}", cause);
             tokio::time::sleep(Duration::from_secs(5)).await;
             continue;
@@ -53,7 +58,16 @@ async fn announce_task(content: HashAndFormat, ep: Endpoint, secret_key: SecretK
 }
 
 /// Server mode - shares a file or directory
-async fn share(path: PathBuf) -> Result<()> {
+///
+/// The content is announced to the tracker periodically. If an access list file is given, only nodes allowed by it can download.
+/// If a metrics address is given, the endpoint and provider metrics are
+/// served on it.
+async fn share(
+    path: PathBuf,
+    tracker: NodeId,
+    acl: Option<PathBuf>,
+    metrics_addr: Option<SocketAddr>,
+) -> Result<()> {
     // Always convert to absolute path
     let absolute_path = env::current_dir()?.join(path);
 
@@ -69,7 +83,7 @@ async fn share(path: PathBuf) -> Result<()> {
     );
 
     // Get or generate a secret key
-    let secret_key = util::get_or_generate_secret_key()?;
+    let secret_key = keys::get_or_generate_secret_key()?;
 
     // Create a blob store
     let blobs_path = create_send_dir()?;
@@ -89,11 +103,12 @@ async fn share(path: PathBuf) -> Result<()> {
     println!("Node ID: {}", node_id);
     println!("Full address: {:?}", addr);
 
-    let tag = util::import(absolute_path.clone(), &blobs).await?;
+    let tag = collection::import(absolute_path.clone(), &blobs).await?;
     let announce_task = tokio::spawn(announce_task(
         *tag.hash_and_format(),
         ep.clone(),
         secret_key,
+        tracker,
     ));
     let ticket = BlobTicket::new(addr, *tag.hash(), tag.format());
     println!("Sharing {}", absolute_path.display());
@@ -105,15 +120,32 @@ async fn share(path: PathBuf) -> Result<()> {
     );
     println!();
 
-    let (dump_task, dump_sender) = util::dump_provider_events();
+    let provider_metrics = Arc::new(metrics::ProviderMetrics::default());
+    let metrics_task = metrics_addr.map(|addr| {
+        let mut registry = Registry::default();
+        registry.register(provider_metrics.clone());
+        common::metrics::serve(addr, &ep, registry)
+    });
+    let (dump_task, dump_sender) = util::dump_provider_events(provider_metrics);
 
     // Create a router with the endpoint
-    let router = Router::builder(ep.clone())
-        .accept(
-            iroh_blobs::ALPN,
-            Blobs::new(&blobs, ep.clone(), Some(dump_sender)),
-        )
-        .spawn();
+    let blobs_protocol = Blobs::new(&blobs, ep.clone(), Some(dump_sender));
+    let mut reload_task = None;
+    let router = match acl {
+        Some(path) => {
+            let access = access::AccessControl::from_file(path)?;
+            reload_task = Some(access.spawn_reload_task());
+            Router::builder(ep.clone())
+                .accept(
+                    iroh_blobs::ALPN,
+                    access::AccessLimited::new(blobs_protocol, access),
+                )
+                .spawn()
+        }
+        None => Router::builder(ep.clone())
+            .accept(iroh_blobs::ALPN, blobs_protocol)
+            .spawn(),
+    };
 
     println!("Server is running. Press Ctrl+C to stop...");
 
@@ -127,6 +159,12 @@ async fn share(path: PathBuf) -> Result<()> {
     // Abort the dump task
     dump_task.abort();
     announce_task.abort();
+    if let Some(reload_task) = reload_task {
+        reload_task.abort();
+    }
+    if let Some(metrics_task) = metrics_task {
+        metrics_task.abort();
+    }
 
     // Remove the blobs directory
     tokio::fs::remove_dir_all(blobs_path).await?;
@@ -134,8 +172,12 @@ async fn share(path: PathBuf) -> Result<()> {
     Ok(())
 }
 
-/// Client mode - receives a file
-async fn receive(content: &str) -> Result<()> {
+/// Client mode - receives a file from the providers the tracker knows
+///
+/// With `diag`, prints the path to every provider whenever it changes. The
+/// connections are owned by the downloader, so there are no connection
+/// statistics to print at the end.
+async fn receive(content: &str, tracker: NodeId, diag: bool) -> Result<()> {
     let content = HashAndFormat::from_str(content).context("invalid content")?;
 
     // Create a blob store
@@ -148,13 +190,14 @@ async fn receive(content: &str) -> Result<()> {
         .add_discovery(|_| discovery::pkarr::dht::DhtDiscovery::builder().build().ok())
         .bind()
         .await?;
+    let diag_task = diag.then(|| diag::watch_paths(ep.clone()));
 
     // Connect to the node
     let downloader = store.downloader(&ep);
     info!("Getting hash sequence");
     let options = DownloadOptions::new(
         content,
-        TrackerDiscovery::new(ep.clone(), TRACKER.parse()?),
+        TrackerDiscovery::new(ep.clone(), tracker),
         SplitStrategy::None,
     );
     // let mut stream = downloader.download(content, nodes).stream().await?;
@@ -164,8 +207,11 @@ async fn receive(content: &str) -> Result<()> {
     }
     info!("Exporting file");
     let collection = Collection::load(content.hash, store.deref()).await?;
-    util::export(&store, collection).await?;
+    collection::export(&store, collection).await?;
 
+    if let Some(diag_task) = diag_task {
+        diag_task.abort();
+    }
     // close the endpoint, just to be nice
     ep.close().await;
     // shutdown the store to sync to disk
@@ -180,25 +226,61 @@ async fn main() -> Result<()> {
     // Initialize tracing with default configuration
     tracing_subscriber::fmt::init();
 
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let acl = cli::take_option(&mut args, "--acl")?.map(PathBuf::from);
+    let diag = cli::take_flag(&mut args, "--diag");
+    let tracker: NodeId = cli::take_option(&mut args, "--tracker")?
+        .as_deref()
+        .unwrap_or(TRACKER)
+        .parse()
+        .context("invalid --tracker")?;
+    let metrics_addr = cli::take_option(&mut args, "--metrics-addr")?
+        .map(|addr| addr.parse::<SocketAddr>())
+        .transpose()
+        .context("invalid --metrics-addr")?;
     let cmd = args.get(1).map(|x| x.to_lowercase()).unwrap_or_default();
-    match cmd.as_str() {
+    let res = match cmd.as_str() {
         "share" if args.len() == 3 => {
             // Server mode - share a file or directory
             let path = PathBuf::from(&args[2]);
-            share(path).await
+            share(path, tracker, acl, metrics_addr).await
         }
         "receive" | "recv" if args.len() == 3 => {
             // Client mode - receive a file or directory
             let content = &args[2];
-            receive(content).await
+            receive(content, tracker, diag).await
+        }
+        "key" => {
+            // Manage named identity profiles
+            keys::key_command(&args[2..])
+        }
+        "ticket" => {
+            // Inspect and build tickets
+            ticket::ticket_command(&args[2..])
         }
         _ => {
             println!("Usage: sendme4 <command> [args]");
             println!("Commands:");
             println!("  share <dir_path>   Share a directory");
             println!("  receive <hash>     Receive a directory");
+            println!("  key <command>      Manage identity profiles");
+            println!("  ticket <command>   Inspect and build tickets");
+            println!("Options:");
+            println!("  --tracker <node id>");
+            println!("                     Announce to and query this tracker");
+            println!("  --acl <file>       Only serve nodes allowed by an access list");
+            println!("  --diag             Print path changes of the connections, without");
+            println!("                     statistics, the downloader owns the connections");
+            println!("  --metrics-addr <ip:port>");
+            println!("                     Serve Prometheus metrics on this address");
             process::exit(1);
         }
+    };
+    if let Err(cause) = &res {
+        if let Some(reason) = access::denied_reason(cause) {
+            eprintln!("Connection rejected by the remote node: {}", reason);
+            process::exit(2);
+        }
     }
+    res
 }
@@ -0,0 +1,19 @@
+//! Metrics of the blobs provider, counted from the provider events
+//!
+//! They are served next to the endpoint metrics by [`common::metrics`] when
+//! `--metrics-addr` is given.
+use iroh_metrics::{Counter, MetricsGroup};
+
+/// Metrics of the blobs provider
+#[derive(Debug, Default, MetricsGroup)]
+#[metrics(name = "provider")]
+pub struct ProviderMetrics {
+    /// Number of clients that connected
+    pub clients_connected: Counter,
+    /// Number of get requests received
+    pub get_requests: Counter,
+    /// Number of transfers that completed
+    pub transfers_completed: Counter,
+    /// Number of transfers that were aborted
+    pub transfers_aborted: Counter,
+}
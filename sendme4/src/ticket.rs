@@ -0,0 +1,205 @@
+//! Local ticket inspection and construction.
+//!
+//! Decodes node tickets, blob tickets and bare node ids (hex or z32), so
+//! tickets never have to be pasted into a website to see what is inside.
+use std::{collections::BTreeSet, net::SocketAddr, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use common::cli::{take_flag, take_option};
+use iroh::{NodeAddr, NodeId, PublicKey, RelayUrl};
+use iroh_base::ticket::NodeTicket;
+use iroh_blobs::{ticket::BlobTicket, BlobFormat, Hash};
+use serde::Serialize;
+
+/// A decoded ticket or node id
+#[derive(Debug, Clone)]
+pub enum Decoded {
+    NodeId(NodeId),
+    Node(NodeTicket),
+    Blob(BlobTicket),
+}
+
+impl Decoded {
+    /// Decode a node ticket, a blob ticket, or a node id in hex or z32
+    pub fn parse(input: &str) -> Result<Self> {
+        let input = input.trim();
+        if let Ok(ticket) = NodeTicket::from_str(input) {
+            return Ok(Self::Node(ticket));
+        }
+        if let Ok(ticket) = BlobTicket::from_str(input) {
+            return Ok(Self::Blob(ticket));
+        }
+        if let Ok(node_id) = NodeId::from_str(input) {
+            return Ok(Self::NodeId(node_id));
+        }
+        if let Ok(bytes) = zbase32::decode_full_bytes_str(input) {
+            if let Ok(bytes) = <[u8; 32]>::try_from(bytes) {
+                return Ok(Self::NodeId(PublicKey::from_bytes(&bytes)?));
+            }
+        }
+        bail!("not a node ticket, blob ticket or node id: {}", input)
+    }
+
+    /// The address contained in the ticket, only the node id for a bare node id
+    pub fn node_addr(&self) -> NodeAddr {
+        match self {
+            Self::NodeId(node_id) => NodeAddr::new(*node_id),
+            Self::Node(ticket) => ticket.node_addr().clone(),
+            Self::Blob(ticket) => ticket.node_addr().clone(),
+        }
+    }
+
+    /// The contents of the ticket in a form that is easy to print
+    pub fn info(&self) -> TicketInfo {
+        let addr = self.node_addr();
+        let (kind, hash, format) = match self {
+            Self::NodeId(_) => ("node id", None, None),
+            Self::Node(_) => ("node ticket", None, None),
+            Self::Blob(ticket) => (
+                "blob ticket",
+                Some(ticket.hash().to_string()),
+                Some(format_name(ticket.format()).to_string()),
+            ),
+        };
+        TicketInfo {
+            kind,
+            node_id: addr.node_id.to_string(),
+            node_id_z32: zbase32::encode_full_bytes(addr.node_id.as_bytes()),
+            relay_url: addr.relay_url.map(|url| url.to_string()),
+            direct_addresses: addr
+                .direct_addresses
+                .iter()
+                .map(|a| a.to_string())
+                .collect(),
+            hash,
+            format,
+        }
+    }
+}
+
+fn format_name(format: BlobFormat) -> &'static str {
+    match format {
+        BlobFormat::Raw => "raw",
+        BlobFormat::HashSeq => "hash-seq",
+    }
+}
+
+/// Everything a ticket contains
+#[derive(Debug, Serialize)]
+pub struct TicketInfo {
+    pub kind: &'static str,
+    pub node_id: String,
+    pub node_id_z32: String,
+    pub relay_url: Option<String>,
+    pub direct_addresses: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+impl std::fmt::Display for TicketInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Kind:             {}", self.kind)?;
+        writeln!(f, "Node ID:          {}", self.node_id)?;
+        writeln!(f, "Node ID (z32):    {}", self.node_id_z32)?;
+        writeln!(
+            f,
+            "Relay URL:        {}",
+            self.relay_url.as_deref().unwrap_or("none")
+        )?;
+        if self.direct_addresses.is_empty() {
+            writeln!(f, "Direct addresses: none")?;
+        }
+        for (i, addr) in self.direct_addresses.iter().enumerate() {
+            let label = if i == 0 { "Direct addresses:" } else { "" };
+            writeln!(f, "{:17} {}", label, addr)?;
+        }
+        if let Some(hash) = &self.hash {
+            writeln!(f, "Hash:             {}", hash)?;
+        }
+        if let Some(format) = &self.format {
+            writeln!(f, "Format:           {}", format)?;
+        }
+        Ok(())
+    }
+}
+
+/// Handle the `ticket` subcommand
+pub fn ticket_command(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let json = take_flag(&mut args, "--json");
+    let relay = take_option(&mut args, "--relay")?
+        .map(|url| RelayUrl::from_str(&url))
+        .transpose()
+        .context("invalid --relay")?;
+    let no_relay = take_flag(&mut args, "--no-relay");
+    let no_direct = take_flag(&mut args, "--no-direct");
+    let mut addrs = BTreeSet::new();
+    while let Some(addr) = take_option(&mut args, "--addr")? {
+        addrs.insert(SocketAddr::from_str(&addr).context("invalid --addr")?);
+    }
+    let hash = take_option(&mut args, "--hash")?
+        .map(|hash| Hash::from_str(&hash))
+        .transpose()
+        .context("invalid --hash")?;
+    let hash_seq = take_flag(&mut args, "--hash-seq");
+    let cmd = args.first().map(|x| x.to_lowercase()).unwrap_or_default();
+    match cmd.as_str() {
+        "inspect" if args.len() == 2 => {
+            let info = Decoded::parse(&args[1])?.info();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                print!("{}", info);
+            }
+        }
+        "build" if args.len() == 2 => {
+            let decoded = Decoded::parse(&args[1])?;
+            let mut addr = decoded.node_addr();
+            if no_relay {
+                addr.relay_url = None;
+            }
+            if relay.is_some() {
+                addr.relay_url = relay;
+            }
+            if no_direct {
+                addr.direct_addresses.clear();
+            }
+            addr.direct_addresses.extend(addrs);
+            let format = if hash_seq {
+                BlobFormat::HashSeq
+            } else {
+                BlobFormat::Raw
+            };
+            let ticket = match (decoded, hash) {
+                (_, Some(hash)) => BlobTicket::new(addr, hash, format).to_string(),
+                (Decoded::Blob(ticket), None) => {
+                    BlobTicket::new(addr, ticket.hash(), ticket.format()).to_string()
+                }
+                (_, None) => NodeTicket::new(addr).to_string(),
+            };
+            println!("{}", ticket);
+        }
+        _ => {
+            println!("Ticket commands:");
+            println!("  ticket inspect <ticket> [--json]");
+            println!(
+                "                               Show what a node ticket, blob ticket or node id"
+            );
+            println!("                               (hex or z32) contains");
+            println!("  ticket build <ticket> [options]");
+            println!(
+                "                               Build a new ticket from the parts of another one"
+            );
+            println!("Build options:");
+            println!("  --relay <url>                Replace the relay URL");
+            println!("  --no-relay                   Remove the relay URL");
+            println!("  --no-direct                  Remove the direct addresses");
+            println!("  --addr <ip:port>             Add a direct address, can be repeated");
+            println!("  --hash <hash> [--hash-seq]   Build a blob ticket for this content");
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}